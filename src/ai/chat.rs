@@ -0,0 +1,123 @@
+use oobabooga_rs::{ChatRequest, History, Mode};
+use serde::Deserialize;
+
+use crate::character::load_character;
+use crate::config::get_ini_value;
+use crate::trace;
+
+fn chat_client() -> oobabooga_rs::Client {
+    let mut ai_config = oobabooga_rs::Config::default();
+    ai_config.url = get_ini_value("chat_ai", "url").unwrap();
+    oobabooga_rs::Client::new(ai_config)
+}
+
+pub fn chat_request(history: History) -> ChatRequest {
+    let mut chat_config = oobabooga_rs::ChatRequest::default();
+    chat_config.mode = Mode::Chat;
+    chat_config.character = get_ini_value("chat_ai", "character").unwrap();
+    chat_config.your_name = get_ini_value("chat_ai", "your_name").unwrap();
+
+    chat_config.history = history;
+    chat_config.regenerate = false;
+    chat_config._continue = true;
+    chat_config.stop_at_newline = false;
+    chat_config.chat_prompt_size = 2048;
+    chat_config.chat_generation_attempts = 1;
+    chat_config.chat_instruct_command = "Continue the chat dialogue below. Write a single reply for the character \"Assistant\"\n\n".to_string();
+    chat_config.max_new_tokens = 250;
+    chat_config.do_sample = true;
+    chat_config.temprature = 0.7;
+    chat_config.top_p = 0.1;
+    chat_config.typical_p = 1.0;
+    chat_config.epsilon_cutoff = 0.0;
+    chat_config.eta_cutoff = 0.0;
+    chat_config.tfs = 0;
+    chat_config.top_a = 0;
+    chat_config.repetition_penalty = 1.18;
+    chat_config.top_k = 40;
+    chat_config.min_length = 0;
+    chat_config.no_repeat_ngram_size = 0;
+    chat_config.num_beams = 1;
+    chat_config.penalty_alpha = 0.0;
+    chat_config.length_penalty = 1.0;
+    chat_config.early_stopping = false;
+    chat_config.mirostat_mode = 0;
+    chat_config.mirostat_mode_tau = 5;
+    chat_config.mirostat_mode_eta = 0.1;
+    chat_config.seed = -1;
+    chat_config.add_bos_token = true;
+    chat_config.truncation_length = 2048;
+    chat_config.ban_eos_token = false;
+    chat_config.skip_special_tokens = true;
+    chat_config.stopping_strings = vec![];
+    chat_config
+}
+
+/// Puts what the user wrote back into the last exchange of the history the model
+/// answered with, the input also had what only this prompt needs, like the time.
+pub fn restore_user_input(history: &mut History, input: &str) {
+    for exchanges in [&mut history.internal, &mut history.visible] {
+        if let Some(exchange) = exchanges.last_mut() {
+            exchange[0] = input.to_string();
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponse {
+    results: Vec<ChatResult>,
+}
+#[derive(Deserialize, Debug)]
+struct ChatResult {
+    history: History,
+}
+
+/// Sends the chat request to oobabooga.
+/// When a character card is loaded its definition is sent along, so the
+/// persona doesn't have to exist on the oobabooga side.
+pub async fn get_chat(
+    chat_config: ChatRequest,
+) -> Result<History, Box<dyn std::error::Error + Send + Sync>> {
+    let request = serde_json::to_value(&chat_config).unwrap_or_default();
+    trace::exchange_result("chat", request, send_chat(chat_config)).await
+}
+
+async fn send_chat(
+    chat_config: ChatRequest,
+) -> Result<History, Box<dyn std::error::Error + Send + Sync>> {
+    let character = match load_character() {
+        Some(character) => character,
+        None => return chat_client().get_chat(chat_config).await,
+    };
+    let mut body = serde_json::to_value(&chat_config)?;
+    if let Some(body) = body.as_object_mut() {
+        body.remove("character");
+        body.insert("name1".to_string(), chat_config.your_name.clone().into());
+        body.insert("name2".to_string(), character.name.clone().into());
+        body.insert(
+            "context".to_string(),
+            character.context(&chat_config.your_name).into(),
+        );
+        body.insert(
+            "greeting".to_string(),
+            character.greeting(&chat_config.your_name).into(),
+        );
+    }
+    let client = reqwest::Client::new();
+    let response: ChatResponse = client
+        .post(format!(
+            "{}/api/v1/chat",
+            get_ini_value("chat_ai", "url")
+                .unwrap()
+                .trim_end_matches('/')
+        ))
+        .json(&body)
+        .send()
+        .await?
+        .json()
+        .await?;
+    match response.results.into_iter().next() {
+        Some(result) => Ok(result.history),
+        None => Err("no results in chat response".into()),
+    }
+}
@@ -0,0 +1,531 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Error, ErrorKind},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use async_tungstenite::tungstenite::{self, Message};
+use base64::{engine::general_purpose, Engine as _};
+use futures_util::{Stream, StreamExt};
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::{
+    ai::{
+        image::{batch_path, save_image, ImageBackend, ImageInfo, Progress, ProgressState},
+        image_settings::{setting, ImageSettings},
+    },
+    config::get_ini_value,
+    trace,
+};
+
+/// ComfyUI, running the workflow templates from `[sd_ai] workflow` and `img2img_workflow`.
+/// Strings like "{{prompt}}" or "{{seed}}" in them are replaced, see values.
+pub struct ComfyUi;
+
+/// what the websocket last said about the running workflows by prompt id,
+/// with the path their images are saved at
+static PROGRESS: Mutex<BTreeMap<String, (String, Progress)>> = Mutex::new(BTreeMap::new());
+
+fn progress_state() -> MutexGuard<'static, BTreeMap<String, (String, Progress)>> {
+    PROGRESS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn url() -> String {
+    get_ini_value("sd_ai", "url")
+        .unwrap_or_default()
+        .trim()
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// a workflow in the api format, saved with "Save (API Format)" in comfyui
+fn workflow(key: &str, default: &str) -> Result<Value, Error> {
+    let path = setting(key, default.to_string());
+    let json = std::fs::read_to_string(&path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("could not read the workflow {}: {}", path, e),
+        )
+    })?;
+    serde_json::from_str(&json).map_err(Error::other)
+}
+
+/// the comfyui name of an automatic1111 sampler, true when it asks for the karras scheduler
+fn sampler(name: &str) -> (String, bool) {
+    let name = name.trim().to_lowercase();
+    let karras = name.ends_with(" karras");
+    let name = name.trim_end_matches(" karras");
+    let (name, ancestral) = match name.strip_suffix(" a") {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    let mut name = name
+        .replace("dpm++", "dpmpp")
+        .replace("dpm2", "dpm_2")
+        .replace("unipc", "uni_pc")
+        .replace(' ', "_");
+    if ancestral {
+        name += "_ancestral";
+    }
+    (name, karras)
+}
+
+/// A lora tag like `<lora:name:0.8>` in the prompt becomes lora_name and lora_strength,
+/// without one lora_name is empty and the LoraLoader is skipped, see skip_empty_loras.
+fn values(prompt: &str, settings: &ImageSettings, seed: i64) -> HashMap<&'static str, Value> {
+    let lora = Regex::new(r"<lora:([^:>]+)(?::([\d.]+))?>").unwrap();
+    let file = |name: &str| match name.contains('.') {
+        true => name.to_string(),
+        false => format!("{}.safetensors", name),
+    };
+    let (lora_name, lora_strength) = match lora.captures(prompt) {
+        Some(captures) => (
+            file(&captures[1]),
+            captures
+                .get(2)
+                .and_then(|strength| strength.as_str().parse().ok())
+                .unwrap_or(1.0),
+        ),
+        None => ("".to_string(), 0.0),
+    };
+    let prompt = lora
+        .replace_all(prompt, "")
+        .split(',')
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (sampler, karras) = sampler(&settings.sampler);
+    let scheduler = match settings.scheduler.as_str() {
+        "" if karras => "karras".to_string(),
+        "" => "normal".to_string(),
+        scheduler => scheduler.to_lowercase(),
+    };
+    HashMap::from([
+        ("prompt", json!(prompt)),
+        ("negative_prompt", json!(settings.negative_prompt)),
+        ("seed", json!(seed)),
+        ("width", json!(settings.width)),
+        ("height", json!(settings.height)),
+        ("steps", json!(settings.steps)),
+        ("cfg", json!(settings.cfg_scale)),
+        ("sampler", json!(sampler)),
+        ("scheduler", json!(scheduler)),
+        ("batch_size", json!(settings.batch_size)),
+        ("denoise", json!(settings.img2img_strength)),
+        (
+            "checkpoint",
+            json!(setting(
+                "checkpoint",
+                "v1-5-pruned-emaonly.safetensors".to_string()
+            )),
+        ),
+        ("lora_name", json!(lora_name)),
+        ("lora_strength", json!(lora_strength)),
+    ])
+}
+
+/// Replaces the placeholders in the template. A placeholder that is the whole
+/// string keeps the type of its value, so "{{seed}}" becomes a number.
+fn fill(template: &Value, values: &HashMap<&str, Value>) -> Value {
+    match template {
+        Value::String(text) => {
+            let key = text
+                .strip_prefix("{{")
+                .and_then(|key| key.strip_suffix("}}"));
+            if let Some(value) = key.and_then(|key| values.get(key)) {
+                return value.clone();
+            }
+            let mut text = text.clone();
+            for (key, value) in values {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                text = text.replace(&format!("{{{{{}}}}}", key), &value);
+            }
+            Value::String(text)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|item| fill(item, values)).collect()),
+        Value::Object(nodes) => Value::Object(
+            nodes
+                .iter()
+                .map(|(key, value)| (key.clone(), fill(value, values)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// a LoraLoader without a lora or at strength 0 passes its model and clip on,
+/// so one template works without a lora
+fn skip_empty_loras(workflow: &mut Value) {
+    let nodes = match workflow.as_object_mut() {
+        Some(nodes) => nodes,
+        None => return,
+    };
+    loop {
+        let empty = nodes.iter().find(|(_, node)| {
+            node["class_type"] == "LoraLoader"
+                && (node["inputs"]["lora_name"] == "" || node["inputs"]["strength_model"] == 0.0)
+        });
+        let (id, model, clip) = match empty {
+            Some((id, node)) => (
+                id.clone(),
+                node["inputs"]["model"].clone(),
+                node["inputs"]["clip"].clone(),
+            ),
+            None => return,
+        };
+        nodes.remove(&id);
+        for node in nodes.values_mut() {
+            if let Some(inputs) = node["inputs"].as_object_mut() {
+                for input in inputs.values_mut() {
+                    if input[0] == id.as_str() {
+                        *input = if input[1] == 0 {
+                            model.clone()
+                        } else {
+                            clip.clone()
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// what ImageInfo reports for automatic1111, comfyui only returns the images
+fn info(prompt: &str, settings: &ImageSettings, seed: i64) -> ImageInfo {
+    let model = setting("checkpoint", "v1-5-pruned-emaonly.safetensors".to_string());
+    let infotext = format!(
+        "{}\nNegative prompt: {}\nSteps: {}, Sampler: {}, CFG scale: {}, Seed: {}, Size: {}x{}, Model: {}",
+        prompt,
+        settings.negative_prompt,
+        settings.steps,
+        settings.sampler,
+        settings.cfg_scale,
+        seed,
+        settings.width,
+        settings.height,
+        model
+    );
+    ImageInfo {
+        prompt: prompt.to_string(),
+        negative_prompt: settings.negative_prompt.clone(),
+        seed,
+        sampler_name: settings.sampler.clone(),
+        sd_model_name: Some(model),
+        steps: settings.steps,
+        cfg_scale: settings.cfg_scale,
+        width: settings.width,
+        height: settings.height,
+        infotexts: vec![infotext],
+        ..Default::default()
+    }
+}
+
+/// Comfyui wants a seed, -1 picks one. It has no variation seeds,
+/// so a variation runs with its subseed or a new seed instead.
+fn seed(settings: &ImageSettings) -> i64 {
+    let seed = match settings.subseed_strength > 0.0 {
+        true => settings.subseed,
+        false => settings.seed,
+    };
+    match seed {
+        seed if seed < 0 => rand::random::<u32>() as i64,
+        seed => seed,
+    }
+}
+
+/// uploads a photo for a LoadImage node, returns its name on the server
+async fn upload(client: &reqwest::Client, image: &[u8]) -> Result<String, Error> {
+    let part = reqwest::multipart::Part::bytes(image.to_vec())
+        .file_name(format!("waifu_bot_{:x}.png", rand::random::<u32>()));
+    let form = reqwest::multipart::Form::new()
+        .part("image", part)
+        .text("overwrite", "true");
+    let response: Value = client
+        .post(format!("{}/upload/image", url()))
+        .multipart(form)
+        .send()
+        .await
+        .map_err(Error::other)?
+        .json()
+        .await
+        .map_err(Error::other)?;
+    match response["name"].as_str() {
+        Some(name) => Ok(name.to_string()),
+        None => Err(Error::other(format!(
+            "comfyui did not take the photo: {}",
+            response
+        ))),
+    }
+}
+
+/// Queues the workflow and follows its progress over the websocket,
+/// then saves the images it made at path, see batch_path.
+async fn run(mut workflow: Value, info: ImageInfo, path: &str) -> Result<Vec<ImageInfo>, Error> {
+    skip_empty_loras(&mut workflow);
+    let client = reqwest::Client::new();
+    let client_id = format!("waifu_bot_{:x}", rand::random::<u64>());
+    // listen before queueing, so no progress is missed
+    let socket_url = format!(
+        "{}/ws?clientId={}",
+        url().replacen("http", "ws", 1),
+        client_id
+    );
+    let socket = match async_tungstenite::tokio::connect_async(socket_url).await {
+        Ok((socket, _)) => Some(socket),
+        Err(e) => {
+            log::error!(
+                "no progress from comfyui, its websocket did not open {:?}",
+                e
+            );
+            None
+        }
+    };
+    let response: Value = client
+        .post(format!("{}/prompt", url()))
+        .json(&json!({ "prompt": workflow, "client_id": client_id }))
+        .send()
+        .await
+        .map_err(Error::other)?
+        .json()
+        .await
+        .map_err(Error::other)?;
+    let prompt_id = match response["prompt_id"].as_str() {
+        Some(id) => id.to_string(),
+        None => {
+            return Err(Error::other(format!(
+                "comfyui did not queue the workflow: {}",
+                response
+            )))
+        }
+    };
+    if let Some(mut socket) = socket {
+        let result = follow(&mut socket, &prompt_id, path).await;
+        progress_state().remove(&prompt_id);
+        result?;
+    }
+    let images = outputs(&client, &prompt_id).await?;
+    let mut infos = vec![];
+    for (index, image) in images.into_iter().enumerate() {
+        let parameters = info.infotexts.first().cloned().unwrap_or_default();
+        save_image(image, &batch_path(path, index), &parameters)?;
+        infos.push(info.clone());
+    }
+    Ok(infos)
+}
+
+/// reads the websocket until the workflow is done, keeping its progress for progress()
+async fn follow<S>(socket: &mut S, prompt_id: &str, path: &str) -> Result<(), Error>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let started = Instant::now();
+    while let Some(message) = socket.next().await {
+        match message.map_err(Error::other)? {
+            Message::Text(text) => {
+                let event: Value = serde_json::from_str(&text).unwrap_or_default();
+                let data = &event["data"];
+                // older versions leave the prompt id out of progress events
+                if data["prompt_id"].as_str().is_some_and(|id| id != prompt_id) {
+                    continue;
+                }
+                match event["type"].as_str().unwrap_or_default() {
+                    "progress" => {
+                        let step = data["value"].as_u64().unwrap_or_default() as u32;
+                        let steps = data["max"].as_u64().unwrap_or(1).max(1) as u32;
+                        let done = step as f32 / steps as f32;
+                        let elapsed = started.elapsed().as_secs_f32();
+                        let mut followed = progress_state();
+                        let current_image = followed
+                            .remove(prompt_id)
+                            .and_then(|(_, progress)| progress.current_image);
+                        let progress = Progress {
+                            progress: done,
+                            eta_relative: if done > 0.0 {
+                                elapsed / done - elapsed
+                            } else {
+                                0.0
+                            },
+                            state: ProgressState {
+                                sampling_step: step,
+                                sampling_steps: steps,
+                            },
+                            current_image,
+                        };
+                        followed.insert(prompt_id.to_string(), (path.to_string(), progress));
+                    }
+                    "executing" if data["node"].is_null() => return Ok(()),
+                    "execution_success" => return Ok(()),
+                    "execution_error" => {
+                        return Err(Error::other(format!(
+                            "the workflow failed: {}",
+                            data["exception_message"]
+                        )))
+                    }
+                    "execution_interrupted" => {
+                        return Err(Error::new(
+                            ErrorKind::Interrupted,
+                            "the workflow has been interrupted",
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            // a preview is its event type 1, the image format and the image
+            Message::Binary(data) if data.len() > 8 && data[..4] == [0, 0, 0, 1] => {
+                if let Some((_, progress)) = progress_state().get_mut(prompt_id) {
+                    progress.current_image = Some(general_purpose::STANDARD.encode(&data[8..]));
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(Error::other("comfyui closed the websocket"))
+}
+
+/// Waits for the workflow in /history and downloads the images it saved through /view.
+/// Without the websocket this is how the end of the workflow is noticed.
+async fn outputs(client: &reqwest::Client, prompt_id: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let interval = Duration::from_secs_f32(setting("progress_interval", 2.0f32).max(0.05));
+    let entry = loop {
+        let history: Value = client
+            .get(format!("{}/history/{}", url(), prompt_id))
+            .send()
+            .await
+            .map_err(Error::other)?
+            .json()
+            .await
+            .map_err(Error::other)?;
+        let entry = &history[prompt_id];
+        if entry["status"]["status_str"] == "error" {
+            return Err(Error::other(format!(
+                "the workflow failed: {}",
+                entry["status"]
+            )));
+        }
+        if !entry.is_null() {
+            break entry.clone();
+        }
+        tokio::time::sleep(interval).await;
+    };
+    let mut images = vec![];
+    for output in entry["outputs"]
+        .as_object()
+        .into_iter()
+        .flat_map(|o| o.values())
+    {
+        for image in output["images"].as_array().into_iter().flatten() {
+            // PreviewImage nodes only leave temp files
+            if image["type"] != "output" {
+                continue;
+            }
+            let text = |key: &str| image[key].as_str().unwrap_or_default().to_string();
+            let data = client
+                .get(format!("{}/view", url()))
+                .query(&[
+                    ("filename", text("filename")),
+                    ("subfolder", text("subfolder")),
+                    ("type", text("type")),
+                ])
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(Error::other)?
+                .bytes()
+                .await
+                .map_err(Error::other)?;
+            images.push(data.to_vec());
+        }
+    }
+    if images.is_empty() {
+        return Err(Error::other("the workflow saved no image"));
+    }
+    Ok(images)
+}
+
+#[async_trait]
+impl ImageBackend for ComfyUi {
+    async fn generate(
+        &self,
+        prompt: String,
+        settings: &ImageSettings,
+        path: &str,
+    ) -> Result<Vec<ImageInfo>, Error> {
+        let seed = seed(settings);
+        let request = json!({ "prompt": prompt, "seed": seed });
+        let result: Result<Vec<ImageInfo>, String> = trace::exchange("comfyui", request, async {
+            let template = workflow("workflow", "./config/comfyui_workflow.json")
+                .map_err(|e| e.to_string())?;
+            let workflow = fill(&template, &values(&prompt, settings, seed));
+            run(workflow, info(&prompt, settings, seed), path)
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        result.map_err(Error::other)
+    }
+
+    /// the photo and mask go to "{{image}}" and "{{mask}}", e.g. of LoadImage nodes
+    async fn edit(
+        &self,
+        prompt: String,
+        settings: &ImageSettings,
+        image: &[u8],
+        mask: Option<&[u8]>,
+        path: &str,
+    ) -> Result<Vec<ImageInfo>, Error> {
+        let seed = seed(settings);
+        let request = json!({ "prompt": prompt, "seed": seed, "img2img": true });
+        let result: Result<Vec<ImageInfo>, String> = trace::exchange("comfyui", request, async {
+            let template = workflow("img2img_workflow", "./config/comfyui_img2img.json")
+                .map_err(|e| e.to_string())?;
+            let client = reqwest::Client::new();
+            let mut values = values(&prompt, settings, seed);
+            let image = upload(&client, image).await.map_err(|e| e.to_string())?;
+            values.insert("image", json!(image));
+            if let Some(mask) = mask {
+                if !template.to_string().contains("{{mask}}") {
+                    log::info!("the img2img workflow has no {{{{mask}}}}, the mask is left out");
+                }
+                let mask = upload(&client, mask).await.map_err(|e| e.to_string())?;
+                values.insert("mask", json!(mask));
+            }
+            run(
+                fill(&template, &values),
+                info(&prompt, settings, seed),
+                path,
+            )
+            .await
+            .map_err(|e| e.to_string())
+        })
+        .await;
+        result.map_err(Error::other)
+    }
+
+    async fn progress(&self, path: &str, with_image: bool) -> Option<Progress> {
+        let mut progress = progress_state()
+            .values()
+            .find(|(followed, _)| followed == path)
+            .map(|(_, progress)| progress.clone())?;
+        if !with_image {
+            progress.current_image = None;
+        }
+        Some(progress)
+    }
+
+    async fn interrupt(&self) {
+        let res = reqwest::Client::new()
+            .post(format!("{}/interrupt", url()))
+            .send()
+            .await;
+        if let Err(e) = res {
+            log::error!("could not interrupt the workflow {:?}", e);
+        }
+    }
+}
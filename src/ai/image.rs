@@ -1,4 +1,4 @@
-use base64::decode;
+use base64::{engine::general_purpose, Engine as _};
 use reqwest::header;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -6,49 +6,305 @@ use std::fs::File;
 use std::io::Error;
 use std::io::Write;
 
+use async_trait::async_trait;
+
+use crate::ai::comfyui::ComfyUi;
+use crate::ai::image_settings::ImageSettings;
 use crate::config;
+use crate::trace;
 use config::get_ini_value;
-// !TODO should return result of the generation
-pub async fn generate_image(prompt: String) -> Result<(), Error> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert("Content-Type", "application/json".parse().unwrap());
+
+/// What stable diffusion reports about an image it generated, the `info` of its answer.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ImageInfo {
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub seed: i64,
+    pub subseed: i64,
+    pub subseed_strength: f32,
+    pub sampler_name: String,
+    pub sd_model_name: Option<String>,
+    pub steps: u32,
+    pub cfg_scale: f32,
+    pub width: u32,
+    pub height: u32,
+    /// the parameters as automatic1111 writes them into its pngs
+    pub infotexts: Vec<String>,
+    /// one for every picture of a batch
+    pub all_prompts: Vec<String>,
+    pub all_seeds: Vec<i64>,
+    pub all_subseeds: Vec<i64>,
+}
+
+impl ImageInfo {
+    /// the info of one picture of a batch
+    pub fn for_image(&self, index: usize) -> ImageInfo {
+        let mut info = self.clone();
+        info.prompt = self.all_prompts.get(index).unwrap_or(&self.prompt).clone();
+        info.seed = *self.all_seeds.get(index).unwrap_or(&self.seed);
+        info.subseed = *self.all_subseeds.get(index).unwrap_or(&self.subseed);
+        info.infotexts = self.infotexts.get(index).cloned().into_iter().collect();
+        info.all_prompts = vec![];
+        info.all_seeds = vec![];
+        info.all_subseeds = vec![];
+        info
+    }
+}
+
+/// where picture index of a batch is saved, the first one at path
+pub fn batch_path(path: &str, index: usize) -> String {
+    match index {
+        0 => path.to_string(),
+        _ => format!("{}_{}.png", path.trim_end_matches(".png"), index),
+    }
+}
+
+/// generates the pictures of a finished prompt, see ai::prompt::compose, and saves them
+/// at path, see batch_path
+pub async fn generate_image_to(
+    prompt: String,
+    settings: &ImageSettings,
+    path: &str,
+) -> Result<Vec<ImageInfo>, Error> {
+    backend().generate(prompt, settings, path).await
+}
+
+/// Changes a photo the user sent to fit the prompt with img2img,
+/// only the white part of the mask is painted over when there is one.
+pub async fn edit_image(
+    prompt: String,
+    settings: &ImageSettings,
+    image: &[u8],
+    mask: Option<&[u8]>,
+    path: &str,
+) -> Result<Vec<ImageInfo>, Error> {
+    let mut settings = settings.clone();
+    // keep the shape of the photo, img2img crops it to the size otherwise
+    if let Some((width, height)) = image_size(image) {
+        settings.fit_to(width, height);
+    }
+    backend().edit(prompt, &settings, image, mask, path).await
+}
+
+/// how far the running generation is, like /sdapi/v1/progress
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Progress {
+    /// 0 to 1
+    pub progress: f32,
+    /// seconds left
+    pub eta_relative: f32,
+    #[serde(default)]
+    pub state: ProgressState,
+    /// base64 image of the current step, when asked for
+    pub current_image: Option<String>,
+}
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ProgressState {
+    #[serde(default)]
+    pub sampling_step: u32,
+    #[serde(default)]
+    pub sampling_steps: u32,
+}
+
+/// how far the generation saving its images at path is, the image of the current step
+/// only when asked for
+pub async fn progress(path: &str, with_image: bool) -> Option<Progress> {
+    backend().progress(path, with_image).await
+}
+
+/// stops the running generation, it returns what it has so far
+pub async fn interrupt() {
+    backend().interrupt().await
+}
+
+/// writes the image of the current step to path
+pub fn save_preview(image_base64: &str, path: &str) -> Result<(), Error> {
+    let data = general_purpose::STANDARD
+        .decode(image_base64)
+        .map_err(Error::other)?;
+    std::fs::write(path, data)
+}
+
+/// A stable diffusion server, chosen with `[sd_ai] backend`.
+#[async_trait]
+pub trait ImageBackend: Send + Sync {
+    /// generates the pictures of the prompt and saves them at path, see batch_path
+    async fn generate(
+        &self,
+        prompt: String,
+        settings: &ImageSettings,
+        path: &str,
+    ) -> Result<Vec<ImageInfo>, Error>;
+    /// changes the photo to fit the prompt, only the white part of the mask when there is one
+    async fn edit(
+        &self,
+        prompt: String,
+        settings: &ImageSettings,
+        image: &[u8],
+        mask: Option<&[u8]>,
+        path: &str,
+    ) -> Result<Vec<ImageInfo>, Error>;
+    /// path tells apart the generations running at once, see generate
+    async fn progress(&self, path: &str, with_image: bool) -> Option<Progress>;
+    async fn interrupt(&self);
+}
+
+/// automatic1111 unless `[sd_ai] backend` is comfyui
+pub fn backend() -> Box<dyn ImageBackend> {
+    match get_ini_value("sd_ai", "backend").unwrap_or_default().trim() {
+        "comfyui" => Box::new(ComfyUi),
+        _ => Box::new(Automatic1111),
+    }
+}
+
+/// the web ui of automatic1111 started with --api
+pub struct Automatic1111;
+
+#[async_trait]
+impl ImageBackend for Automatic1111 {
+    async fn generate(
+        &self,
+        prompt: String,
+        settings: &ImageSettings,
+        path: &str,
+    ) -> Result<Vec<ImageInfo>, Error> {
+        let image_request = user_context(prompt, settings);
+        let request = serde_json::to_value(&image_request).unwrap_or_default();
+        let result: Result<Vec<ImageInfo>, String> = trace::exchange("image", request, async {
+            sd_request("txt2img", &image_request, path)
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        result.map_err(Error::other)
+    }
+
+    async fn edit(
+        &self,
+        prompt: String,
+        settings: &ImageSettings,
+        image: &[u8],
+        mask: Option<&[u8]>,
+        path: &str,
+    ) -> Result<Vec<ImageInfo>, Error> {
+        let mut context = user_context(prompt, settings);
+        context.enable_hr = false;
+        context.denoising_strength = settings.img2img_strength;
+        let image_request = Img2ImgContext {
+            context,
+            init_images: vec![general_purpose::STANDARD.encode(image)],
+            mask: mask.map(|mask| general_purpose::STANDARD.encode(mask)),
+            mask_blur: 4,
+            inpainting_fill: 1,
+            inpaint_full_res: false,
+            resize_mode: 0,
+        };
+        let request = serde_json::to_value(&image_request).unwrap_or_default();
+        let result: Result<Vec<ImageInfo>, String> = trace::exchange("img2img", request, async {
+            sd_request("img2img", &image_request, path)
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        result.map_err(Error::other)
+    }
+
+    /// automatic1111 only reports the generation it is working on
+    async fn progress(&self, _path: &str, with_image: bool) -> Option<Progress> {
+        let url = get_ini_value("sd_ai", "url")?;
+        let response = reqwest::Client::new()
+            .get(format!("{}/sdapi/v1/progress", url.trim_end_matches('/')))
+            .query(&[("skip_current_image", (!with_image).to_string())])
+            .send()
+            .await;
+        let progress = match response {
+            Ok(response) => response.json::<Progress>().await,
+            Err(e) => Err(e),
+        };
+        match progress {
+            Ok(progress) => Some(progress),
+            Err(e) => {
+                log::error!("could not get the progress {:?}", e);
+                None
+            }
+        }
+    }
+
+    async fn interrupt(&self) {
+        let url = get_ini_value("sd_ai", "url").unwrap_or_default();
+        let res = reqwest::Client::new()
+            .post(format!("{}/sdapi/v1/interrupt", url.trim_end_matches('/')))
+            .send()
+            .await;
+        if let Err(e) = res {
+            log::error!("could not interrupt the generation {:?}", e);
+        }
+    }
+}
+
+/// width and height from the header of a png or jpeg
+fn image_size(data: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |at: usize| Some(u16::from_be_bytes([*data.get(at)?, *data.get(at + 1)?]) as u32);
+    let be32 = |at: usize| Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    if data.starts_with(b"\x89PNG") {
+        return Some((be32(16)?, be32(20)?));
+    }
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    // walk the jpeg segments to the start of frame
+    let mut at = 2;
+    while *data.get(at)? == 0xFF {
+        let marker = *data.get(at + 1)?;
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            return Some((be16(at + 7)?, be16(at + 5)?));
+        }
+        at += 2 + be16(at + 2)? as usize;
+    }
+    None
+}
+
+fn user_context(prompt: String, settings: &ImageSettings) -> UserContext {
     let override_settings = HashMap::new();
     // override_settings.insert("key1".to_string(), "value1".to_string());
 
     let alwayson_scripts = HashMap::new();
     // alwayson_scripts.insert("script1".to_string(), "value1".to_string());
-    let image_request = UserContext {
-        enable_hr: false,
-        denoising_strength: 0,
+    UserContext {
+        enable_hr: settings.enable_hr,
+        denoising_strength: settings.denoising_strength,
         firstphase_height: 0,
         firstphase_width: 0,
-        hr_scale: 2,
-        hr_upscaler: "".to_string(),
-        hr_second_pass_steps: 0,
+        hr_scale: settings.hr_scale,
+        hr_upscaler: settings.hr_upscaler.clone(),
+        hr_second_pass_steps: settings.hr_second_pass_steps,
         prompt,
-        styles: vec![],
-        seed: -1,
-        subseed: -1,
+        styles: settings.styles.clone(),
+        seed: settings.seed,
+        subseed: settings.subseed,
         hr_resize_x: 0,
         hr_resize_y: 0,
         hr_sampler_name: "".to_string(),
         hr_prompt: "".to_string(),
         hr_negative_prompt: "".to_string(),
-        subseed_strength: 0,
+        subseed_strength: settings.subseed_strength,
         seed_resize_from_h: -1,
         seed_resize_from_w: -1,
-        sampler_name: "".to_string(),
-        batch_size: 1,
+        sampler_name: settings.sampler.clone(),
+        scheduler: settings.scheduler.clone(),
+        batch_size: settings.batch_size,
         n_iter: 1,
-        steps: 50,
-        cfg_scale: 7,
-        width: 512,
-        height: 512,
-        restore_faces: false,
+        steps: settings.steps,
+        cfg_scale: settings.cfg_scale,
+        width: settings.width,
+        height: settings.height,
+        restore_faces: settings.restore_faces,
         tiling: false,
         do_not_save_samples: false,
-        do_not_save_grid: false,
-        negative_prompt: get_ini_value("sd_ai", "negative_promt").unwrap(),
+        // the grid would come back as the first image of a batch
+        do_not_save_grid: true,
+        negative_prompt: settings.negative_prompt.clone(),
         eta: 0,
         s_min_uncond: 0,
         s_churn: 0,
@@ -58,23 +314,36 @@ pub async fn generate_image(prompt: String) -> Result<(), Error> {
         override_settings,
         override_settings_restore_afterwards: true,
         script_args: vec![],
-        sampler_index: "Euler".to_string(),
+        sampler_index: settings.sampler.clone(),
         script_name: "".to_string(),
         send_images: true,
         save_images: false,
         alwayson_scripts,
-    };
+    }
+}
+
+/// Posts the request to an automatic1111 endpoint, e.g. txt2img, and saves the images it returns
+/// at path with their generation parameters, see batch_path. Returns the seed, sampler and model
+/// of every image.
+async fn sd_request<T: Serialize>(
+    endpoint: &str,
+    image_request: &T,
+    path: &str,
+) -> Result<Vec<ImageInfo>, Error> {
+    let mut headers = header::HeaderMap::new();
+    headers.insert("Content-Type", "application/json".parse().unwrap());
     let client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
         .build()
         .unwrap();
     let response = client
         .post(format!(
-            "{}/sdapi/v1/txt2img",
-            get_ini_value("sd_ai", "url").expect("No stable diffusion url defined")
+            "{}/sdapi/v1/{}",
+            get_ini_value("sd_ai", "url").expect("No stable diffusion url defined"),
+            endpoint
         ))
         .headers(headers)
-        .body(serde_json::to_string(&image_request).unwrap())
+        .body(serde_json::to_string(image_request).unwrap())
         .send()
         .await;
 
@@ -85,8 +354,25 @@ pub async fn generate_image(prompt: String) -> Result<(), Error> {
         match res_image {
             Ok(res) => {
                 let image: GeneratedImage = res;
-                let result = save_without_splitting_image(image.images[0].to_string());
-                return result;
+                let info: ImageInfo = serde_json::from_str(&image.info).unwrap_or_else(|e| {
+                    log::error!("could not read the image info {:?}", e);
+                    ImageInfo::default()
+                });
+                if image.images.is_empty() {
+                    return Err(Error::other("stable diffusion returned no image"));
+                }
+                let mut infos = vec![];
+                for (index, image) in image.images.iter().enumerate() {
+                    let info = info.for_image(index);
+                    let parameters = info.infotexts.first().cloned().unwrap_or_default();
+                    save_without_splitting_image(
+                        image.to_string(),
+                        &batch_path(path, index),
+                        &parameters,
+                    )?;
+                    infos.push(info);
+                }
+                return Ok(infos);
             }
             Err(e) => {
                 log::error!("Error {} when generating image", e);
@@ -97,22 +383,26 @@ pub async fn generate_image(prompt: String) -> Result<(), Error> {
             }
         }
     }
-    Ok(())
-
-    // return Ok(image)
+    Err(Error::new(
+        std::io::ErrorKind::ConnectionRefused,
+        "Could not reach stable diffusion",
+    ))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GeneratedImage {
     images: Vec<String>,
+    /// json with the parameters, see ImageInfo
+    #[serde(default)]
+    info: String,
 }
 #[derive(Serialize, Deserialize, Debug)]
 struct UserContext {
     enable_hr: bool,
-    denoising_strength: u32,
+    denoising_strength: f32,
     firstphase_width: u32,
     firstphase_height: u32,
-    hr_scale: u32,
+    hr_scale: f32,
     hr_upscaler: String,
     hr_second_pass_steps: u32,
     hr_resize_x: u32,
@@ -122,16 +412,19 @@ struct UserContext {
     hr_negative_prompt: String,
     prompt: String,
     styles: Vec<String>,
-    seed: i32,
-    subseed: i32,
-    subseed_strength: u32,
+    seed: i64,
+    subseed: i64,
+    subseed_strength: f32,
     seed_resize_from_h: i32,
     seed_resize_from_w: i32,
     sampler_name: String,
+    /// only sent when set, older servers choose the scheduler from the sampler
+    #[serde(skip_serializing_if = "String::is_empty")]
+    scheduler: String,
     batch_size: u32,
     n_iter: u32,
     steps: u32,
-    cfg_scale: u32,
+    cfg_scale: f32,
     width: u32,
     height: u32,
     restore_faces: bool,
@@ -155,15 +448,100 @@ struct UserContext {
     alwayson_scripts: std::collections::HashMap<String, String>,
 }
 
-fn save_without_splitting_image(image_base64: String) -> std::io::Result<()> {
-    let image_data = decode(image_base64);
+/// the img2img request is the txt2img one with the photo and mask added
+#[derive(Serialize, Deserialize, Debug)]
+struct Img2ImgContext {
+    #[serde(flatten)]
+    context: UserContext,
+    init_images: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mask: Option<String>,
+    mask_blur: u32,
+    /// 1 starts the masked part from the original instead of noise
+    inpainting_fill: u32,
+    inpaint_full_res: bool,
+    resize_mode: u32,
+}
+
+/// decodes an image a server answered with as base64 and saves it with its parameters
+pub fn save_without_splitting_image(
+    image_base64: String,
+    file_path: &str,
+    parameters: &str,
+) -> std::io::Result<()> {
+    let image_data = general_purpose::STANDARD
+        .decode(image_base64)
+        .map_err(Error::other)?;
+    save_image(image_data, file_path, parameters)
+}
+
+/// saves an image at file_path, a png with the generation parameters in it
+pub fn save_image(image_data: Vec<u8>, file_path: &str, parameters: &str) -> std::io::Result<()> {
+    let image_data = with_text_chunk(image_data, "parameters", parameters);
 
-    let file_path = "./out/output_image.png";
     let file = File::create(file_path);
     if let Ok(mut f) = file {
-        f.write_all(&image_data.unwrap())?;
+        f.write_all(&image_data)?;
         log::debug!("Image saved to {}", file_path);
         return Ok(());
     }
     Err(file.unwrap_err())
 }
+
+/// Adds a text chunk after the header of a png, automatic1111 and other tools read the
+/// generation parameters back from "parameters". Anything but a png is returned unchanged,
+/// as is a png that already has the chunk.
+fn with_text_chunk(png: Vec<u8>, keyword: &str, text: &str) -> Vec<u8> {
+    // the signature and the IHDR chunk
+    const HEADER_END: usize = 8 + 25;
+    let is_png = png.starts_with(b"\x89PNG\r\n\x1a\n") && png.get(12..16) == Some(b"IHDR");
+    if !is_png || png.len() < HEADER_END || text.is_empty() || has_text_chunk(&png, keyword) {
+        return png;
+    }
+    // tEXt is latin-1, iTXt takes utf-8 after its flags and empty language tags
+    let mut chunk = if text.is_ascii() {
+        format!("tEXt{}\0{}", keyword, text)
+    } else {
+        format!("iTXt{}\0\0\0\0\0{}", keyword, text)
+    }
+    .into_bytes();
+    let length = (chunk.len() - 4) as u32;
+    let crc = crc32(&chunk);
+    let mut result = png[..HEADER_END].to_vec();
+    result.extend(length.to_be_bytes());
+    result.append(&mut chunk);
+    result.extend(crc.to_be_bytes());
+    result.extend(&png[HEADER_END..]);
+    result
+}
+
+fn has_text_chunk(png: &[u8], keyword: &str) -> bool {
+    let prefix = format!("{}\0", keyword);
+    let mut at = 8;
+    while let Some(length) = png.get(at..at + 4) {
+        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+        let kind = png.get(at + 4..at + 8).unwrap_or_default();
+        let data = png.get(at + 8..at + 8 + length).unwrap_or_default();
+        if (kind == b"tEXt" || kind == b"iTXt") && data.starts_with(prefix.as_bytes()) {
+            return true;
+        }
+        at += 12 + length;
+    }
+    false
+}
+
+/// the crc of png chunks, over their type and data
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,309 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_ini_value;
+
+/// How stable diffusion generates an image, read from the sd_ai section
+/// and overridable per request with e.g. `--ar 2:3 --steps 30`.
+/// Settings saved without a field get it from the config.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ImageSettings {
+    pub width: u32,
+    pub height: u32,
+    pub steps: u32,
+    pub cfg_scale: f32,
+    pub sampler: String,
+    /// empty uses the default scheduler of the sampler
+    pub scheduler: String,
+    pub seed: i64,
+    /// with subseed_strength above 0 a variation of the seed
+    pub subseed: i64,
+    pub subseed_strength: f32,
+    pub styles: Vec<String>,
+    /// how many pictures are generated at once
+    pub batch_size: u32,
+    pub restore_faces: bool,
+    pub enable_hr: bool,
+    pub hr_scale: f32,
+    pub hr_upscaler: String,
+    pub hr_second_pass_steps: u32,
+    pub denoising_strength: f32,
+    /// how much img2img changes a photo, 0 keeps it and 1 ignores it
+    pub img2img_strength: f32,
+    pub positive_prompt: String,
+    pub negative_prompt: String,
+}
+
+/// a value from the sd_ai section, default when it is empty or invalid
+pub fn setting<T: FromStr>(key: &str, default: T) -> T {
+    get_ini_value("sd_ai", key)
+        .filter(|value| !value.trim().is_empty())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// width and height of a size preset
+fn preset(name: &str) -> Option<(u32, u32)> {
+    match name.trim().to_lowercase().as_str() {
+        "square" => Some((512, 512)),
+        "portrait" => Some((512, 768)),
+        "landscape" => Some((768, 512)),
+        "square_xl" => Some((1024, 1024)),
+        "portrait_xl" => Some((832, 1216)),
+        "landscape_xl" => Some((1216, 832)),
+        _ => None,
+    }
+}
+
+/// stable diffusion wants sizes in multiples of 8
+fn round_to_8(value: f32) -> u32 {
+    ((value / 8.0).round() as u32).max(1) * 8
+}
+
+impl Default for ImageSettings {
+    fn default() -> Self {
+        Self::from_config()
+    }
+}
+
+impl ImageSettings {
+    pub fn from_config() -> Self {
+        let mut settings = ImageSettings {
+            width: setting("width", 512),
+            height: setting("height", 512),
+            steps: setting("steps", 50),
+            cfg_scale: setting("cfg_scale", 7.0),
+            sampler: setting("sampler", "Euler".to_string()),
+            scheduler: setting("scheduler", "".to_string()),
+            seed: setting("seed", -1),
+            subseed: -1,
+            subseed_strength: 0.0,
+            styles: setting("styles", "".to_string())
+                .split(',')
+                .map(|style| style.trim().to_string())
+                .filter(|style| !style.is_empty())
+                .collect(),
+            batch_size: 1,
+            restore_faces: setting("restore_faces", false),
+            enable_hr: setting("enable_hr", false),
+            hr_scale: setting("hr_scale", 2.0),
+            hr_upscaler: setting("hr_upscaler", "".to_string()),
+            hr_second_pass_steps: setting("hr_second_pass_steps", 0),
+            denoising_strength: setting("denoising_strength", 0.0),
+            img2img_strength: setting("img2img_strength", 0.6),
+            positive_prompt: setting("positive_promt", "".to_string()),
+            negative_prompt: setting("negative_promt", "".to_string()),
+        };
+        if let Some((width, height)) = preset(&setting("size", "".to_string())) {
+            settings.width = width;
+            settings.height = height;
+        }
+        settings
+    }
+
+    /// keeps the short side and stretches the long side to the aspect ratio, e.g. "2:3"
+    fn set_aspect_ratio(&mut self, ratio: &str) -> bool {
+        let (w, h) = match ratio.split_once(':') {
+            Some((w, h)) => match (w.trim().parse::<f32>(), h.trim().parse::<f32>()) {
+                (Ok(w), Ok(h)) if w > 0.0 && h > 0.0 => (w, h),
+                _ => return false,
+            },
+            None => return false,
+        };
+        let short = self.width.min(self.height) as f32;
+        if w >= h {
+            self.width = round_to_8(short * w / h);
+            self.height = round_to_8(short);
+        } else {
+            self.width = round_to_8(short);
+            self.height = round_to_8(short * h / w);
+        }
+        true
+    }
+
+    /// keeps the short side and takes the aspect ratio of a photo
+    pub fn fit_to(&mut self, width: u32, height: u32) {
+        if width > 0 && height > 0 {
+            self.set_aspect_ratio(&format!("{}:{}", width, height));
+        }
+    }
+
+    /// between 1 and `[sd_ai] max_batch` pictures at once
+    pub fn set_batch_size(&mut self, count: u32) {
+        self.batch_size = count.clamp(1, setting("max_batch", 4u32).max(1));
+    }
+
+    /// The same picture at hr_scale times the size: hires fix for txt2img,
+    /// img2img takes the bigger size directly.
+    pub fn upscale(&mut self, img2img: bool) {
+        if img2img {
+            self.width = round_to_8(self.width as f32 * self.hr_scale);
+            self.height = round_to_8(self.height as f32 * self.hr_scale);
+        } else {
+            self.enable_hr = true;
+            if self.hr_upscaler.is_empty() {
+                self.hr_upscaler = "Latent".to_string();
+            }
+            if self.denoising_strength == 0.0 {
+                self.denoising_strength = 0.5;
+            }
+        }
+    }
+
+    /// Applies one `--name value` option, an error for the user when the value is invalid.
+    /// Sizes, steps and batches are clamped to the limits of the config.
+    fn apply(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("--{} cannot be {}.", name, value);
+        fn parse<T: FromStr>(value: &str, invalid: impl Fn() -> String) -> Result<T, String> {
+            value.parse().map_err(|_| invalid())
+        }
+        // strengths are fractions
+        let fraction = || match parse::<f32>(value, invalid)? {
+            value if (0.0..=1.0).contains(&value) => Ok(value),
+            _ => Err(invalid()),
+        };
+        let max_size = setting("max_size", 2048u32).max(64);
+        match name {
+            "ar" | "aspect" => {
+                if !self.set_aspect_ratio(value) {
+                    return Err(invalid());
+                }
+            }
+            "size" => {
+                let (width, height) = preset(value).ok_or_else(invalid)?;
+                self.width = width;
+                self.height = height;
+            }
+            "w" | "width" => self.width = parse::<u32>(value, invalid)?,
+            "h" | "height" => self.height = parse::<u32>(value, invalid)?,
+            "steps" => {
+                let steps: u32 = parse(value, invalid)?;
+                self.steps = steps.clamp(1, setting("max_steps", 150u32).max(1));
+            }
+            "cfg" | "cfg_scale" => match parse::<f32>(value, invalid)? {
+                cfg if cfg > 0.0 => self.cfg_scale = cfg,
+                _ => return Err(invalid()),
+            },
+            "seed" => self.seed = parse(value, invalid)?,
+            "subseed" => self.subseed = parse(value, invalid)?,
+            "variation" => self.subseed_strength = fraction()?,
+            "n" | "batch" => self.set_batch_size(parse(value, invalid)?),
+            "sampler" => self.sampler = value.to_string(),
+            "scheduler" => self.scheduler = value.to_string(),
+            "style" => self.styles.push(value.to_string()),
+            "hr" | "hires" => self.enable_hr = !matches!(value, "off" | "false" | "no"),
+            "upscaler" => {
+                self.enable_hr = true;
+                self.hr_upscaler = value.to_string();
+            }
+            "hr_scale" => match parse::<f32>(value, invalid)? {
+                scale if (1.0..=4.0).contains(&scale) => self.hr_scale = scale,
+                _ => return Err(invalid()),
+            },
+            "denoise" | "denoising_strength" => self.denoising_strength = fraction()?,
+            "strength" => self.img2img_strength = fraction()?,
+            "restore_faces" | "faces" => {
+                self.restore_faces = !matches!(value, "off" | "false" | "no")
+            }
+            "neg" | "negative" => {
+                if !self.negative_prompt.is_empty() {
+                    self.negative_prompt += ", ";
+                }
+                self.negative_prompt += value;
+            }
+            _ => return Err(format!("There is no picture option --{}.", name)),
+        }
+        // stable diffusion needs sizes that are a multiple of 8
+        self.width = self.width.clamp(64, max_size) / 8 * 8;
+        self.height = self.height.clamp(64, max_size) / 8 * 8;
+        Ok(())
+    }
+}
+
+/// options that work without a value, e.g. `--hr`
+fn is_switch(name: &str) -> bool {
+    matches!(name, "hr" | "hires" | "restore_faces" | "faces")
+}
+
+/// A word of a message with where it starts and ends. A quoted word, e.g. "DPM++ 2M",
+/// is one word without the quotes.
+struct Word<'a> {
+    start: usize,
+    end: usize,
+    text: &'a str,
+    quoted: bool,
+}
+
+fn words(text: &str) -> Vec<Word<'_>> {
+    let mut words = vec![];
+    let mut rest = text.char_indices().peekable();
+    while let Some((start, c)) = rest.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let quoted = c == '"';
+        let stop = |c: char| if quoted { c == '"' } else { c.is_whitespace() };
+        let mut end = text.len();
+        while let Some(&(index, c)) = rest.peek() {
+            if stop(c) {
+                end = index;
+                break;
+            }
+            rest.next();
+        }
+        if quoted {
+            // past the closing quote
+            rest.next();
+        }
+        words.push(Word {
+            start,
+            end: (end + quoted as usize).min(text.len()),
+            text: &text[start + quoted as usize..end],
+            quoted,
+        });
+    }
+    words
+}
+
+/// Takes the `--name value` options out of a message, anywhere in it, and returns the
+/// message without them and the settings with them applied. Values with spaces are quoted,
+/// e.g. `--sampler "DPM++ 2M"`. An unknown option or an invalid value is an error for the user.
+pub fn parse_overrides(text: &str) -> Result<(String, ImageSettings), String> {
+    let mut settings = ImageSettings::from_config();
+    let words = words(text);
+    let mut remaining = String::new();
+    let mut kept_from = 0;
+    let mut index = 0;
+    while index < words.len() {
+        let word = &words[index];
+        let name = match word.text.strip_prefix("--") {
+            Some(name) if !word.quoted && name.starts_with(|c: char| c.is_alphabetic()) => {
+                name.to_lowercase()
+            }
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        let value = words.get(index + 1).filter(|value| {
+            let option = !value.quoted && value.text.starts_with("--");
+            let switch_value = matches!(value.text, "on" | "off" | "true" | "false" | "yes" | "no");
+            !option && (!is_switch(&name) || switch_value)
+        });
+        if value.is_none() && !is_switch(&name) {
+            return Err(format!("--{} needs a value.", name));
+        }
+        settings.apply(&name, value.map(|value| value.text).unwrap_or_default())?;
+        remaining += &text[kept_from..word.start];
+        kept_from = value.unwrap_or(word).end;
+        index += 1 + value.is_some() as usize;
+    }
+    remaining += &text[kept_from..];
+    let remaining: Vec<&str> = remaining
+        .split(' ')
+        .filter(|part| !part.is_empty())
+        .collect();
+    Ok((remaining.join(" ").trim().to_string(), settings))
+}
@@ -0,0 +1,128 @@
+use std::io::Error;
+
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+
+use crate::{ai::image::save_without_splitting_image, config::get_ini_value, trace};
+
+/// the commands that work on a photo the user sent or the last picture of the chat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tool {
+    Upscale,
+    RestoreFaces,
+    RemoveBackground,
+    Describe,
+}
+
+impl Tool {
+    /// the tool and what follows the command, e.g. "/upscale 4" is Upscale and "4"
+    pub fn from_command(text: &str) -> Option<(Tool, &str)> {
+        let (command, rest) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+        // telegram adds the bot name in groups, e.g. /upscale@waifu_bot
+        let tool = match command.split('@').next()? {
+            "/upscale" => Tool::Upscale,
+            "/restore_faces" => Tool::RestoreFaces,
+            "/remove_bg" => Tool::RemoveBackground,
+            "/describe" => Tool::Describe,
+            _ => return None,
+        };
+        Some((tool, rest.trim()))
+    }
+
+    /// the status while it works
+    pub fn status(&self) -> &'static str {
+        match self {
+            Tool::Upscale => "Upscaling picture...",
+            Tool::RestoreFaces => "Restoring faces...",
+            Tool::RemoveBackground => "Removing background...",
+            Tool::Describe => "Looking at the picture...",
+        }
+    }
+}
+
+fn tools_value(key: &str, default: &str) -> String {
+    get_ini_value("image_tools", key)
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or(default.to_string())
+}
+
+/// Upscales the image with `[image_tools] upscaler`, by scale or `[image_tools] scale`,
+/// and saves it at path.
+pub async fn upscale(image: &[u8], scale: Option<f32>, path: &str) -> Result<(), Error> {
+    let scale = scale.unwrap_or(tools_value("scale", "2").parse().unwrap_or(2.0));
+    extras(
+        image,
+        json!({
+            "upscaling_resize": scale.clamp(1.0, 8.0),
+            "upscaler_1": tools_value("upscaler", "R-ESRGAN 4x+"),
+        }),
+        path,
+    )
+    .await
+}
+
+/// Restores the faces in the image with `[image_tools] face_restorer`, gfpgan or codeformer.
+pub async fn restore_faces(image: &[u8], path: &str) -> Result<(), Error> {
+    let visibility = match tools_value("face_restorer", "codeformer")
+        .to_lowercase()
+        .as_str()
+    {
+        "gfpgan" => "gfpgan_visibility",
+        _ => "codeformer_visibility",
+    };
+    let mut options = json!({ "upscaling_resize": 1, "upscaler_1": "None" });
+    options[visibility] = json!(1);
+    extras(image, options, path).await
+}
+
+/// automatic1111's extras tab for a single image, options are added to the request
+async fn extras(image: &[u8], options: Value, path: &str) -> Result<(), Error> {
+    let url = get_ini_value("sd_ai", "url").unwrap_or_default();
+    let traced = json!({ "options": options, "bytes": image.len() });
+    let mut request = options;
+    request["image"] = json!(general_purpose::STANDARD.encode(image));
+    request["resize_mode"] = json!(0);
+    let response: Value = trace::exchange_result("extras", traced, async {
+        Ok(reqwest::Client::new()
+            .post(format!(
+                "{}/sdapi/v1/extra-single-image",
+                url.trim_end_matches('/')
+            ))
+            .json(&request)
+            .send()
+            .await?
+            .json()
+            .await?)
+    })
+    .await
+    .map_err(Error::other)?;
+    match response["image"].as_str() {
+        Some(image) => save_without_splitting_image(image.to_string(), path, ""),
+        None => Err(Error::other(format!("no image in {}", response))),
+    }
+}
+
+/// Posts the image to a rembg server, `[image_tools] rembg_url`, and saves the png it
+/// answers with at path.
+pub async fn remove_background(image: &[u8], path: &str) -> Result<(), Error> {
+    let url = tools_value("rembg_url", "http://localhost:7000/api/remove");
+    let request = json!({ "url": url, "bytes": image.len() });
+    // the trace keeps the picture as base64, it is no json
+    let png: String = trace::exchange_result("rembg", request, async {
+        let form = reqwest::multipart::Form::new().part(
+            "file",
+            reqwest::multipart::Part::bytes(image.to_vec()).file_name("image.png"),
+        );
+        let response = reqwest::Client::new()
+            .post(&url)
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(general_purpose::STANDARD.encode(response.bytes().await?))
+    })
+    .await
+    .map_err(Error::other)?;
+    save_without_splitting_image(png, path, "")
+}
@@ -1,2 +1,11 @@
-// pub mod chat;
+pub mod chat;
+pub mod comfyui;
 pub mod image;
+pub mod image_settings;
+pub mod image_tools;
+pub mod postprocess;
+pub mod prompt;
+pub mod safety;
+pub mod scene;
+pub mod tools;
+pub mod vision;
@@ -0,0 +1,179 @@
+use oobabooga_rs::History;
+use regex::Regex;
+
+use crate::config::get_ini_value;
+
+const DEFAULT_FILTERS: &str = "impersonation,stop_strings,repeats";
+
+/// Runs the configured filters over the last reply in the history,
+/// so the cleaned text is what gets stored, sent and spoken.
+pub fn clean_history(mut history: History) -> History {
+    if let Some(exchange) = history.internal.last_mut() {
+        if exchange.len() == 2 {
+            exchange[1] = clean(&exchange[1]);
+        }
+    }
+    if let Some(exchange) = history.visible.last_mut() {
+        if exchange.len() == 2 {
+            exchange[1] = clean(&exchange[1]);
+        }
+    }
+    history
+}
+
+pub fn clean(text: &str) -> String {
+    let filters = get_ini_value("postprocess", "filters").unwrap_or(DEFAULT_FILTERS.to_string());
+    let mut text = text.to_string();
+    let setting = |key: &str| get_ini_value("postprocess", key).unwrap_or_default();
+    for filter in filters.split(',').map(|f| f.trim()) {
+        text = match filter {
+            "impersonation" => trim_impersonation(
+                &text,
+                &get_ini_value("chat_ai", "your_name").unwrap_or_default(),
+            ),
+            "stop_strings" => apply_stop_strings(&text, &setting("stop_strings")),
+            "actions" => handle_actions(&text, &setting("actions")),
+            "repeats" => collapse_repeats(&text),
+            "max_length" => enforce_max_length(&text, setting("max_length").parse().unwrap_or(0)),
+            "" => text,
+            _ => {
+                log::error!("unknown postprocess filter {}", filter);
+                text
+            }
+        };
+    }
+    text.trim().to_string()
+}
+
+/// cuts the reply where the model starts writing lines for the user
+fn trim_impersonation(text: &str, your_name: &str) -> String {
+    if your_name.is_empty() {
+        return text.to_string();
+    }
+    let prefix = format!("{}:", your_name.to_lowercase());
+    let mut lines = vec![];
+    for line in text.lines() {
+        if line.trim_start().to_lowercase().starts_with(&prefix) {
+            break;
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// stop_strings are separated by |
+fn apply_stop_strings(text: &str, stop_strings: &str) -> String {
+    let mut text = text.to_string();
+    for stop in stop_strings.split('|').filter(|s| !s.is_empty()) {
+        if let Some(index) = text.find(stop) {
+            text.truncate(index);
+        }
+    }
+    text
+}
+
+/// strips roleplay actions like *smiles*, any other mode keeps them for the
+/// frontend to render, telegram shows them in italics
+fn handle_actions(text: &str, mode: &str) -> String {
+    match mode {
+        "strip" => {
+            let re = Regex::new(r"\*([^*]+)\*").unwrap();
+            let stripped = re.replace_all(text, "");
+            let spaces = Regex::new(r"[ \t]{2,}").unwrap();
+            spaces.replace_all(&stripped, " ").to_string()
+        }
+        _ => text.to_string(),
+    }
+}
+
+fn split_sentences(text: &str) -> Vec<&str> {
+    let re = Regex::new(r"[^.!?\n]+[.!?]*\s*|\n+").unwrap();
+    re.find_iter(text).map(|m| m.as_str()).collect()
+}
+
+/// drops sentences the model already said earlier in the same reply
+fn collapse_repeats(text: &str) -> String {
+    let mut seen: Vec<String> = vec![];
+    let mut out = String::new();
+    for sentence in split_sentences(text) {
+        let normalized = sentence.trim().to_lowercase();
+        if normalized.is_empty() {
+            out.push_str(sentence);
+            continue;
+        }
+        if seen.contains(&normalized) {
+            continue;
+        }
+        seen.push(normalized);
+        out.push_str(sentence);
+    }
+    out
+}
+
+/// shortens the reply to max_length characters, preferably at the end of a sentence
+fn enforce_max_length(text: &str, max_length: usize) -> String {
+    if max_length == 0 || text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    let mut out = String::new();
+    for sentence in split_sentences(text) {
+        if out.chars().count() + sentence.chars().count() > max_length {
+            break;
+        }
+        out.push_str(sentence);
+    }
+    if out.trim().is_empty() {
+        out = text.chars().take(max_length).collect();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_of_the_user_are_cut_off() {
+        assert_eq!(
+            trim_impersonation("hi there\nTester: hello\nmore", "tester"),
+            "hi there"
+        );
+        assert_eq!(
+            trim_impersonation("hi\nTester: hello", ""),
+            "hi\nTester: hello"
+        );
+    }
+
+    #[test]
+    fn the_reply_ends_at_the_first_stop_string() {
+        assert_eq!(apply_stop_strings("hi ### rest", "###|<END>"), "hi ");
+        assert_eq!(apply_stop_strings("hi <END> ###", "###|<END>"), "hi ");
+        assert_eq!(apply_stop_strings("hi", ""), "hi");
+    }
+
+    #[test]
+    fn actions_are_kept_or_stripped() {
+        let text = "*smiles* hello *waves at you* there";
+        assert_eq!(handle_actions(text, "keep"), text);
+        assert_eq!(handle_actions(text, "render"), text);
+        assert_eq!(handle_actions(text, "strip"), " hello there");
+        // an action that is never closed stays as it is
+        assert_eq!(handle_actions("*a* b *c", "strip"), " b *c");
+        assert_eq!(handle_actions("2 * 3 = 6", "strip"), "2 * 3 = 6");
+    }
+
+    #[test]
+    fn repeated_sentences_are_dropped() {
+        assert_eq!(
+            collapse_repeats("I love you. I love you! I love you. Bye."),
+            "I love you. I love you! Bye."
+        );
+    }
+
+    #[test]
+    fn long_replies_end_at_a_sentence() {
+        assert_eq!(enforce_max_length("One. Two. Three.", 10), "One. Two. ");
+        assert_eq!(enforce_max_length("abcdefghij", 4), "abcd");
+        assert_eq!(enforce_max_length("One. Two.", 0), "One. Two.");
+    }
+}
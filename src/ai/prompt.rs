@@ -0,0 +1,163 @@
+use oobabooga_rs::History;
+use regex::Regex;
+
+use crate::ai::{self, image_settings::ImageSettings, scene};
+use crate::config::get_ini_value;
+use crate::message_parsers::has_multiple_self_references;
+
+/// how many exchanges of the conversation the model sees when writing the prompt
+const PROMPT_CONTEXT: usize = 4;
+
+fn sd_value(key: &str) -> String {
+    get_ini_value("sd_ai", key)
+        .map(|value| value.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// "tags" asks the model for booru tags, "caption" for a sentence
+fn prompt_style() -> String {
+    match sd_value("prompt_style").as_str() {
+        "caption" => "caption".to_string(),
+        _ => "tags".to_string(),
+    }
+}
+
+/// the picture is of the character when the user asks for her, or she talks about herself
+pub fn shows_character(request: &str, description: &str) -> bool {
+    let regex = Regex::new(r"\b(you|your|yourself|selfie)\b").unwrap();
+    regex.is_match(&request.to_lowercase()) || has_multiple_self_references(description)
+}
+
+/// the answer of the model as one line, without quotes or a "tags:" label
+fn clean_answer(answer: &str) -> String {
+    let answer = answer.trim();
+    let answer = match answer.split_once(':') {
+        Some((label, rest))
+            if ["tags", "caption", "prompt"].contains(&label.trim().to_lowercase().as_str()) =>
+        {
+            rest
+        }
+        _ => answer,
+    };
+    answer
+        .lines()
+        .map(|line| line.trim().trim_matches('"').trim_end_matches('.').trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Asks the model to turn the picture it just described into tags or a caption
+/// for stable diffusion. The question is not stored in the history.
+pub async fn subject_from_chat(history: &History) -> Option<String> {
+    let instruction = match prompt_style().as_str() {
+        "caption" => "Write the picture you just described as a single sentence caption for an image generator. Only write the caption.",
+        _ => "Write the picture you just described as a comma separated list of danbooru tags for an image generator: subject, clothing, pose, expression, setting and lighting. Only write the tags.",
+    };
+    let recent = |exchanges: &Vec<Vec<String>>| {
+        exchanges[exchanges.len().saturating_sub(PROMPT_CONTEXT)..].to_vec()
+    };
+    let history = History {
+        internal: recent(&history.internal),
+        visible: recent(&history.visible),
+    };
+    ask(history, instruction.to_string()).await
+}
+
+/// tags or a caption for a photo changed as the user asked, e.g. "make this anime style"
+pub async fn subject_from_instruction(instruction: &str) -> Option<String> {
+    let question = match prompt_style().as_str() {
+        "caption" => format!("Write a single sentence caption for an image generator of a photo after this change: \"{}\". Only write the caption.", instruction),
+        _ => format!("Write a comma separated list of danbooru tags for an image generator of a photo after this change: \"{}\". Only write the tags.", instruction),
+    };
+    ask(
+        History {
+            internal: vec![],
+            visible: vec![],
+        },
+        question,
+    )
+    .await
+}
+
+async fn ask(history: History, question: String) -> Option<String> {
+    let answer = answer(history, question).await?;
+    Some(clean_answer(&answer)).filter(|subject| !subject.is_empty())
+}
+
+/// the answer of the model to a question about the conversation, as it is
+pub async fn answer(history: History, question: String) -> Option<String> {
+    let mut chat_config = ai::chat::chat_request(history);
+    chat_config.user_input = question;
+    match ai::chat::get_chat(chat_config).await {
+        Ok(res) => res.last(),
+        Err(e) => {
+            log::error!("could not get a prompt from the model {:?}", e);
+            None
+        }
+    }
+}
+
+/// Puts the positive prompt, the appearance and outfit of the character, the subject,
+/// the rest of the scene and the lora together. Tags that come twice are kept once.
+pub fn compose(
+    subject: &str,
+    shows_character: bool,
+    settings: &ImageSettings,
+    scene: Option<&scene::Scene>,
+) -> String {
+    let appearance = scene::appearance();
+    let scene = scene.cloned().unwrap_or_default();
+    let mut parts = vec![settings.positive_prompt.trim().to_string()];
+    if shows_character {
+        parts.push(appearance.appearance);
+        parts.push(match scene.outfit.is_empty() {
+            true => appearance.outfit,
+            false => scene.outfit,
+        });
+    }
+    parts.push(subject.trim().to_string());
+    if shows_character {
+        parts.push(scene.activity);
+    }
+    parts.push(scene.location);
+    parts.push(scene.time_of_day);
+    if shows_character {
+        parts.push(appearance.lora);
+    }
+    let mut tags: Vec<String> = vec![];
+    for tag in parts.iter().flat_map(|part| part.split(',')) {
+        let tag = tag.trim();
+        if !tag.is_empty() && !tags.iter().any(|seen| seen.eq_ignore_ascii_case(tag)) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags.join(", ")
+}
+
+/// what /lastprompt shows
+pub fn describe(prompt: &str, settings: &ImageSettings) -> String {
+    let mut text = format!("prompt: {}\n", prompt);
+    if !settings.negative_prompt.is_empty() {
+        text += &format!("negative prompt: {}\n", settings.negative_prompt);
+    }
+    text += &format!(
+        "{}x{}, {} steps, cfg {}, {}, seed {}",
+        settings.width,
+        settings.height,
+        settings.steps,
+        settings.cfg_scale,
+        settings.sampler,
+        settings.seed
+    );
+    if settings.batch_size > 1 {
+        text += &format!(", {} pictures", settings.batch_size);
+    }
+    if settings.subseed_strength > 0.0 {
+        text += &format!(
+            ", variation {} of subseed {}",
+            settings.subseed_strength, settings.subseed
+        );
+    }
+    text
+}
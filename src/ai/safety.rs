@@ -0,0 +1,161 @@
+use serde_json::{json, Value};
+
+use crate::{config::get_ini_value, trace};
+
+/// what happens to a picture the classifier finds nsfw
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Allow,
+    /// sent hidden behind a spoiler
+    Blur,
+    Block,
+}
+
+fn safety_value(key: &str, default: &str) -> String {
+    get_ini_value("safety", key)
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or(default.to_string())
+}
+
+pub fn safety_enabled() -> bool {
+    safety_value("enabled", "false") == "true"
+}
+
+/// The role of a chat from `[safety] roles`, e.g. "telegram:-1001234=guest, discord:5678=guest".
+/// Chats that are not listed are "default".
+pub fn role(chat: &str) -> String {
+    safety_value("roles", "")
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .find(|(address, _)| address.trim() == chat)
+        .map(|(_, role)| role.trim().to_string())
+        .unwrap_or("default".to_string())
+}
+
+/// `[safety] <role>_policy`, `policy` for the default role and roles without one
+pub fn policy(role: &str) -> Policy {
+    let policy = get_ini_value("safety", &format!("{}_policy", role))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or(safety_value("policy", "allow"));
+    match policy.as_str() {
+        "allow" => Policy::Allow,
+        "blur" => Policy::Blur,
+        "block" => Policy::Block,
+        other => {
+            log::error!("unknown safety policy {}, blocking", other);
+            Policy::Block
+        }
+    }
+}
+
+/// true when pictures of the chat are classified, its previews are not shown then
+pub fn screened(chat: &str) -> bool {
+    safety_enabled() && policy(&role(chat)) != Policy::Allow
+}
+
+/// `[safety] negative_prompt` and `<role>_negative_prompt`, added to every picture of the chat
+pub fn negative_prompt(role: &str) -> String {
+    if !safety_enabled() {
+        return "".to_string();
+    }
+    [
+        safety_value("negative_prompt", ""),
+        get_ini_value("safety", &format!("{}_negative_prompt", role))
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    ]
+    .into_iter()
+    .filter(|prompt| !prompt.is_empty())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// Decides what happens to a picture sent to or from the chat. Pictures are only
+/// classified when the policy of the chat is not allow, one that cannot be classified
+/// counts as nsfw. Every block is logged.
+pub async fn judge(chat: &str, image: &[u8], source: &str) -> Policy {
+    if !safety_enabled() {
+        return Policy::Allow;
+    }
+    let role = role(chat);
+    let policy = policy(&role);
+    if policy == Policy::Allow {
+        return Policy::Allow;
+    }
+    let threshold: f32 = safety_value("threshold", "0.7").parse().unwrap_or(0.7);
+    let score = match nsfw_score(image).await {
+        Ok(score) => score,
+        Err(e) => {
+            log::error!(
+                "could not classify the {} picture, treating it as nsfw {:?}",
+                source,
+                e
+            );
+            1.0
+        }
+    };
+    if score < threshold {
+        return Policy::Allow;
+    }
+    match policy {
+        Policy::Block => log::warn!(
+            "blocked a {} picture in {} (role {}, nsfw score {:.2})",
+            source,
+            chat,
+            role,
+            score
+        ),
+        _ => log::info!(
+            "blurred a {} picture in {} (role {}, nsfw score {:.2})",
+            source,
+            chat,
+            role,
+            score
+        ),
+    }
+    policy
+}
+
+/// How nsfw the picture is, 0 to 1. The picture is posted as it is to `[safety] url`,
+/// an image classification endpoint answering like huggingface's
+/// `[{"label": "nsfw", "score": 0.98}, {"label": "normal", "score": 0.02}]`.
+/// rust-bert has no image models, so a local onnx classifier runs behind such an endpoint too.
+/// The score is the highest one of the `[safety] labels`.
+async fn nsfw_score(image: &[u8]) -> Result<f32, Box<dyn std::error::Error + Send + Sync>> {
+    let url = safety_value("url", "");
+    if url.is_empty() {
+        return Err("no [safety] url for the classifier".into());
+    }
+    let labels = safety_value("labels", "nsfw,porn,hentai,sexy");
+    let labels: Vec<&str> = labels.split(',').map(|label| label.trim()).collect();
+    let request = json!({ "url": url, "bytes": image.len() });
+    let response: Value = trace::exchange_result("safety", request, async {
+        let mut request = reqwest::Client::new().post(&url).body(image.to_vec());
+        let key = safety_value("key", "");
+        if !key.is_empty() {
+            request = request.bearer_auth(key);
+        }
+        Ok(request.send().await?.json().await?)
+    })
+    .await?;
+    // some servers wrap the list in another one
+    let classes = match &response[0] {
+        Value::Array(classes) => classes.clone(),
+        _ => response
+            .as_array()
+            .cloned()
+            .ok_or(format!("no classes in {}", response))?,
+    };
+    Ok(classes
+        .iter()
+        .filter(|class| {
+            class["label"]
+                .as_str()
+                .is_some_and(|label| labels.iter().any(|l| l.eq_ignore_ascii_case(label)))
+        })
+        .filter_map(|class| class["score"].as_f64())
+        .fold(0.0, f64::max) as f32)
+}
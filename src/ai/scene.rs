@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use oobabooga_rs::History;
+use serde::{Deserialize, Serialize};
+
+use crate::{ai, config::get_ini_value, history};
+
+/// Where the character is and what she is doing, so her pictures match the conversation.
+/// Kept per conversation, updated from the chat before a picture or with /scene.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Scene {
+    pub location: String,
+    pub outfit: String,
+    pub time_of_day: String,
+    pub activity: String,
+}
+
+/// How the character always looks, from `[sd_ai] appearance_sheet`. The sheet is a json
+/// object with an entry for every character, e.g.
+/// `{"mika": {"appearance": "1girl, blue hair", "outfit": "school uniform", "lora": "<lora:mika:0.8>"}}`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Appearance {
+    pub appearance: String,
+    /// what she wears when the scene does not say
+    pub outfit: String,
+    pub lora: String,
+}
+
+fn scene_value(key: &str, default: &str) -> String {
+    get_ini_value("scene", key)
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or(default.to_string())
+}
+
+pub fn scene_enabled() -> bool {
+    scene_value("enabled", "false") == "true"
+}
+
+/// The sheet of the configured character, `[sd_ai] appearance` and `lora` without one.
+pub fn appearance() -> Appearance {
+    let sd_value = |key: &str| {
+        get_ini_value("sd_ai", key)
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    let fallback = Appearance {
+        appearance: sd_value("appearance"),
+        outfit: "".to_string(),
+        lora: sd_value("lora"),
+    };
+    let path = sd_value("appearance_sheet");
+    if path.is_empty() {
+        return fallback;
+    }
+    let sheets: HashMap<String, Appearance> = match std::fs::read_to_string(&path) {
+        Ok(json) => match serde_json::from_str(&json) {
+            Ok(sheets) => sheets,
+            Err(e) => {
+                log::error!("could not read the appearance sheet {}: {:?}", path, e);
+                return fallback;
+            }
+        },
+        Err(_) => return fallback,
+    };
+    let character = get_ini_value("chat_ai", "character").unwrap_or_default();
+    sheets
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(&character))
+        .map(|(_, sheet)| sheet)
+        .unwrap_or(fallback)
+}
+
+impl Scene {
+    pub fn read(conversation: Option<&str>) -> Scene {
+        history::file::read_scene(conversation)
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn write(&self, conversation: Option<&str>) {
+        let res = serde_json::to_string(self)
+            .map_err(|e| e.into())
+            .and_then(|json| history::file::write_scene(&json, conversation));
+        if let Err(e) = res {
+            log::error!("could not store the scene {:?}", e);
+        }
+    }
+
+    /// changes one field from /scene, false when there is no such field
+    pub fn set(&mut self, field: &str, value: &str) -> bool {
+        let target = match field {
+            "location" | "place" => &mut self.location,
+            "outfit" | "clothes" => &mut self.outfit,
+            "time" | "time_of_day" => &mut self.time_of_day,
+            "activity" | "doing" => &mut self.activity,
+            _ => return false,
+        };
+        *target = value.trim().to_string();
+        true
+    }
+
+    /// the fields the other scene knows replace these
+    fn merge(&mut self, other: Scene) {
+        for (target, value) in [
+            (&mut self.location, other.location),
+            (&mut self.outfit, other.outfit),
+            (&mut self.time_of_day, other.time_of_day),
+            (&mut self.activity, other.activity),
+        ] {
+            if !value.trim().is_empty() {
+                *target = value.trim().to_string();
+            }
+        }
+    }
+
+    /// what /scene shows
+    pub fn describe(&self) -> String {
+        let show = |value: &str| match value {
+            "" => "-".to_string(),
+            value => value.to_string(),
+        };
+        format!(
+            "location: {}\noutfit: {}\ntime: {}\nactivity: {}",
+            show(&self.location),
+            show(&self.outfit),
+            show(&self.time_of_day),
+            show(&self.activity)
+        )
+    }
+}
+
+/// the json object in an answer, models like to wrap it in text or a code block
+fn parse_scene(answer: &str) -> Option<Scene> {
+    let start = answer.find('{')?;
+    let end = answer.rfind('}')?;
+    serde_json::from_str(answer.get(start..=end)?).ok()
+}
+
+/// Asks the model where the character is now, starting from the scene so far so
+/// what it does not mention stays. The question is not stored in the history.
+pub async fn update_from_chat(history: &History, scene: &Scene) -> Scene {
+    let context = scene_value("context", "6").parse().unwrap_or(6);
+    let recent = |exchanges: &Vec<Vec<String>>| {
+        exchanges[exchanges.len().saturating_sub(context)..].to_vec()
+    };
+    let history = History {
+        internal: recent(&history.internal),
+        visible: recent(&history.visible),
+    };
+    let question = format!(
+        "The scene so far: {}. Update it from our conversation. Answer only with json with the keys location, outfit, time_of_day and activity, short tags for an image generator, an empty string when unknown.",
+        serde_json::to_string(scene).unwrap_or_default()
+    );
+    let mut updated = scene.clone();
+    match ai::prompt::answer(history, question)
+        .await
+        .as_deref()
+        .map(parse_scene)
+    {
+        Some(Some(found)) => updated.merge(found),
+        Some(None) => log::info!("no scene in the answer of the model"),
+        None => {}
+    }
+    updated
+}
@@ -0,0 +1,410 @@
+use oobabooga_rs::History;
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::ai::chat;
+use crate::character::load_character;
+use crate::config::get_ini_value;
+use crate::modules::{self, pokeapi::PokemonEx};
+use crate::trace;
+
+// how many times the model may call tools before it has to answer
+const MAX_TOOL_ROUNDS: usize = 4;
+
+pub fn tools_enabled() -> bool {
+    get_ini_value("tools", "enabled").unwrap_or_default() == "true"
+}
+
+/// the json schemas of every tool the model is allowed to call
+pub fn tool_schemas() -> Vec<Value> {
+    let mut tools = vec![
+        json!({
+            "type": "function",
+            "function": {
+                "name": "weather",
+                "description": "Get the current weather for a city",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "city": {"type": "string", "description": "name of the city"}
+                    },
+                    "required": ["city"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "pokemon",
+                "description": "Look up types, abilities and weight of a pokemon",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "name": {"type": "string", "description": "name of the pokemon"}
+                    },
+                    "required": ["name"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "memory",
+                "description": "Search the long term memory for something the user said before",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "query": {"type": "string", "description": "what to look for"}
+                    },
+                    "required": ["query"]
+                }
+            }
+        }),
+    ];
+    if get_ini_value("calendar", "enabled").unwrap_or_default() == "true" {
+        tools.push(json!({
+            "type": "function",
+            "function": {
+                "name": "calendar",
+                "description": "Get the appointments of the user for a day",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "date": {"type": "string", "description": "the day in YYYY-MM-DD format, defaults to today"}
+                    }
+                }
+            }
+        }));
+    }
+    if get_ini_value("sd_ai", "enabled").unwrap_or_default() == "true" {
+        tools.push(json!({
+            "type": "function",
+            "function": {
+                "name": "image",
+                "description": "Generate a picture and send it to the user",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "prompt": {"type": "string", "description": "detailed description of the picture"}
+                    },
+                    "required": ["prompt"]
+                }
+            }
+        }));
+    }
+    tools
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolResult {
+    pub name: String,
+    pub content: String,
+    /// what the image tool asked for, generated in the queue once the answer is sent
+    pub picture: Option<String>,
+}
+
+pub async fn execute_tool(name: &str, arguments: &Value) -> ToolResult {
+    log::info!("executing tool {} with {}", name, arguments);
+    let argument = |key: &str| {
+        arguments
+            .get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let mut picture = None;
+    let content = match name {
+        "weather" => modules::weather::get_weather(argument("city"))
+            .await
+            .unwrap_or_else(|| "could not get the weather".to_string()),
+        "pokemon" => match modules::pokeapi::get_pokemon(&argument("name").to_lowercase()).await {
+            Some(pokemon) => pokemon.to_ai_string(),
+            None => "could not find that pokemon".to_string(),
+        },
+        "memory" => match modules::database::get_simmilar(argument("query")).await {
+            Ok(res) => format!(
+                "on {} the user said: {}",
+                res.embedding.metadata.date.format("%Y-%m-%d"),
+                res.embedding.id
+            ),
+            Err(e) => {
+                log::error!("{:?}", e);
+                "nothing found in memory".to_string()
+            }
+        },
+        "calendar" => {
+            let date = chrono::NaiveDate::parse_from_str(&argument("date"), "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(12, 0, 0))
+                .map(|d| d.and_utc())
+                .unwrap_or_else(chrono::Utc::now);
+            modules::calendar::appointments_to_string(date)
+        }
+        // like any other picture it waits in the queue and passes the safety policy
+        "image" => {
+            picture = Some(argument("prompt"));
+            "the picture is being made, it is sent to the user after your answer".to_string()
+        }
+        _ => format!("unknown tool {}", name),
+    };
+    ToolResult {
+        name: name.to_string(),
+        content,
+        picture,
+    }
+}
+
+/// Answers the user with tool calling enabled.
+/// Returns the updated history and the results of every tool that was called.
+pub async fn reply_with_tools(
+    history: &History,
+    message_text: &str,
+    time_context: Option<&str>,
+) -> Result<(History, Vec<ToolResult>), Box<dyn std::error::Error + Send + Sync>> {
+    match get_ini_value("tools", "backend")
+        .unwrap_or_default()
+        .as_str()
+    {
+        "oobabooga" => reply_with_json_tools(history, message_text, time_context).await,
+        _ => reply_with_function_calling(history, message_text, time_context).await,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+impl ChatMessage {
+    fn new(role: &str, content: &str) -> Self {
+        ChatMessage {
+            role: role.to_string(),
+            content: Some(content.to_string()),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    function: FunctionCall,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct FunctionCall {
+    name: String,
+    // openai sends the arguments as a json encoded string
+    arguments: String,
+}
+#[derive(Serialize, Debug)]
+struct CompletionRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    tools: Vec<Value>,
+    tool_choice: String,
+    max_tokens: u32,
+    temperature: f32,
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct CompletionResponse {
+    choices: Vec<Choice>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct Choice {
+    message: ChatMessage,
+}
+
+async fn reply_with_function_calling(
+    history: &History,
+    message_text: &str,
+    time_context: Option<&str>,
+) -> Result<(History, Vec<ToolResult>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut character = get_ini_value("chat_ai", "character").unwrap_or_default();
+    let your_name = get_ini_value("chat_ai", "your_name").unwrap_or_default();
+    let mut context = String::new();
+    if let Some(card) = load_character() {
+        character = card.name.clone();
+        context = card.context(&your_name);
+    }
+    let mut messages = vec![ChatMessage::new(
+        "system",
+        &format!(
+            "You are {}, chatting with {}. Stay in character. Use the tools when they help you answer.\n{}\n{}",
+            character, your_name, context, time_context.unwrap_or_default()
+        ),
+    )];
+    for exchange in &history.internal {
+        if exchange.len() == 2 {
+            messages.push(ChatMessage::new("user", &exchange[0]));
+            messages.push(ChatMessage::new("assistant", &exchange[1]));
+        }
+    }
+    messages.push(ChatMessage::new("user", message_text));
+
+    let mut headers = header::HeaderMap::new();
+    headers.insert("Content-Type", "application/json".parse().unwrap());
+    let key = get_ini_value("tools", "key").unwrap_or_default();
+    if !key.is_empty() {
+        headers.insert("Authorization", format!("Bearer {}", key).parse()?);
+    }
+    let client = reqwest::Client::new();
+    let url = match get_ini_value("tools", "url").filter(|url| !url.is_empty()) {
+        Some(url) => format!("{}/chat/completions", url.trim_end_matches('/')),
+        None => return Err("No tools url defined".into()),
+    };
+
+    let mut results = vec![];
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let request = CompletionRequest {
+            model: get_ini_value("tools", "model").unwrap_or_default(),
+            messages: messages.clone(),
+            tools: tool_schemas(),
+            tool_choice: "auto".to_string(),
+            max_tokens: 250,
+            temperature: 0.7,
+        };
+        let response: CompletionResponse =
+            trace::exchange_result("tools", serde_json::to_value(&request)?, async {
+                Ok(client
+                    .post(&url)
+                    .headers(headers.clone())
+                    .body(serde_json::to_string(&request)?)
+                    .send()
+                    .await?
+                    .json()
+                    .await?)
+            })
+            .await?;
+        let message = match response.choices.into_iter().next() {
+            Some(choice) => choice.message,
+            None => return Err("no choices in completion response".into()),
+        };
+        match message.tool_calls.clone() {
+            Some(calls) if !calls.is_empty() => {
+                messages.push(message);
+                for call in calls {
+                    let arguments =
+                        serde_json::from_str(&call.function.arguments).unwrap_or(Value::Null);
+                    let result = execute_tool(&call.function.name, &arguments).await;
+                    messages.push(ChatMessage {
+                        role: "tool".to_string(),
+                        content: Some(result.content.clone()),
+                        tool_calls: None,
+                        tool_call_id: Some(call.id),
+                    });
+                    results.push(result);
+                }
+            }
+            _ => {
+                let reply = message.content.unwrap_or_default();
+                let mut history = history.clone();
+                history
+                    .internal
+                    .push(vec![message_text.to_string(), reply.clone()]);
+                history.visible.push(vec![message_text.to_string(), reply]);
+                return Ok((history, results));
+            }
+        }
+    }
+    Err("model kept calling tools without answering".into())
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonToolCall {
+    tool: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+fn json_tool_instructions() -> String {
+    let tools: Vec<String> = tool_schemas()
+        .iter()
+        .map(|t| t["function"].to_string())
+        .collect();
+    format!(
+        "|You can use these tools: {} \
+        If one of them is needed to answer, reply with only a json object like \
+        {{\"tool\": \"name\", \"arguments\": {{...}}}} and nothing else. \
+        Otherwise reply normally.|",
+        tools.join(" ")
+    )
+}
+
+/// finds the first json object in a reply that looks like a tool call
+fn parse_json_tool_call(reply: &str) -> Option<JsonToolCall> {
+    let start = reply.find('{')?;
+    let end = reply.rfind('}')?;
+    if end < start {
+        return None;
+    }
+    serde_json::from_str(&reply[start..=end]).ok()
+}
+
+// oobabooga has no native function calling, so the tools are described in
+// the prompt and the model is asked to answer with json instead
+async fn reply_with_json_tools(
+    history: &History,
+    message_text: &str,
+    time_context: Option<&str>,
+) -> Result<(History, Vec<ToolResult>), Box<dyn std::error::Error + Send + Sync>> {
+    // the time is for the prompt only, the history keeps the message
+    let with_time = |input: String| match time_context {
+        Some(time) => format!("{} | {} |", input, time),
+        None => input,
+    };
+    let mut chat_config = chat::chat_request(history.clone());
+    chat_config.user_input = format!(
+        "{} {}",
+        with_time(message_text.to_string()),
+        json_tool_instructions()
+    );
+
+    let mut response = chat::get_chat(chat_config).await?;
+    let call = response.last().and_then(|last| parse_json_tool_call(&last));
+    match call {
+        Some(call) => {
+            let result = execute_tool(&call.tool, &call.arguments).await;
+            let mut chat_config = chat::chat_request(history.clone());
+            let input = format!(
+                "{} | result of the {} tool, use it to answer the user | {}",
+                message_text, result.name, result.content
+            );
+            chat_config.user_input = with_time(input);
+            let mut response = chat::get_chat(chat_config).await?;
+            // the history keeps what the user wrote, not the tool result
+            chat::restore_user_input(&mut response, message_text);
+            Ok((response, vec![result]))
+        }
+        None => {
+            // don't keep the tool instructions in the history
+            chat::restore_user_input(&mut response, message_text);
+            Ok((response, vec![]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_tool_calls_are_found_in_the_reply() {
+        let call =
+            parse_json_tool_call(r#"sure! {"tool": "pokemon", "arguments": {"name": "Pikachu"}}"#)
+                .unwrap();
+        assert_eq!(call.tool, "pokemon");
+        assert_eq!(call.arguments["name"], "Pikachu");
+
+        assert!(parse_json_tool_call("just a normal answer").is_none());
+        assert!(parse_json_tool_call("} not {a call").is_none());
+        assert!(parse_json_tool_call(r#"{"mood": "happy"}"#).is_none());
+    }
+}
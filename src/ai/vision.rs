@@ -0,0 +1,106 @@
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use serde_json::{json, Value};
+
+use crate::config::get_ini_value;
+use crate::trace;
+
+fn vision_value(key: &str, default: &str) -> String {
+    get_ini_value("vision", key)
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or(default.to_string())
+}
+
+pub fn vision_enabled() -> bool {
+    vision_value("enabled", "false") == "true"
+}
+
+/// Describes a photo so the character can react to it, with automatic1111's
+/// interrogate (clip or deepbooru) or a blip/llava style http endpoint.
+pub async fn describe_image(image: &[u8]) -> Option<String> {
+    let backend = vision_value("backend", "interrogate");
+    let request = json!({ "backend": backend, "bytes": image.len() });
+    let description: Option<String> = trace::exchange("vision", request, async {
+        let result = match backend.as_str() {
+            "http" => describe_with_endpoint(image).await,
+            _ => interrogate(image).await,
+        };
+        match result {
+            Ok(description) => Some(description.trim().to_string()).filter(|d| !d.is_empty()),
+            Err(e) => {
+                log::error!("could not describe the photo {:?}", e);
+                None
+            }
+        }
+    })
+    .await;
+    description
+}
+
+async fn interrogate(image: &[u8]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let url = get_ini_value("sd_ai", "url").unwrap_or_default();
+    let response: Value = reqwest::Client::new()
+        .post(format!(
+            "{}/sdapi/v1/interrogate",
+            url.trim_end_matches('/')
+        ))
+        .json(&json!({
+            "image": general_purpose::STANDARD.encode(image),
+            "model": vision_value("model", "clip"),
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    match response["caption"].as_str() {
+        Some(caption) => Ok(caption.to_string()),
+        None => Err(format!("no caption in {}", response).into()),
+    }
+}
+
+/// Posts the photo to `[vision] url`. The body fits ollama's generate api and most
+/// blip servers, the description is read from caption, description, response or text.
+async fn describe_with_endpoint(
+    image: &[u8],
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let image = general_purpose::STANDARD.encode(image);
+    let response: Value = reqwest::Client::new()
+        .post(vision_value("url", "http://localhost:11434/api/generate"))
+        .json(&json!({
+            "model": vision_value("model", "llava"),
+            "prompt": vision_value("prompt", "Describe this photo in detail."),
+            "image": image,
+            "images": [image],
+            "stream": false,
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    for key in ["caption", "description", "response", "text"] {
+        if let Some(description) = response[key].as_str() {
+            return Ok(description.to_string());
+        }
+    }
+    Err(format!("no description in {}", response).into())
+}
+
+/// keeps a photo the user sent in `[vision] dir`, returns its path for the history
+pub fn save_photo(image: &[u8]) -> std::io::Result<String> {
+    let dir = vision_value("dir", "./photos");
+    std::fs::create_dir_all(&dir)?;
+    let extension = if image.starts_with(b"\x89PNG") {
+        "png"
+    } else {
+        "jpg"
+    };
+    let path = format!(
+        "{}/{}.{}",
+        dir.trim_end_matches('/'),
+        Utc::now().format("%Y%m%d_%H%M%S%.3f"),
+        extension
+    );
+    std::fs::write(&path, image)?;
+    Ok(path)
+}
@@ -0,0 +1,240 @@
+use std::fs;
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::get_ini_value;
+
+const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// A SillyTavern / TavernAI character card.
+/// The aliases cover the older pygmalion style field names.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Character {
+    #[serde(default, alias = "char_name")]
+    pub name: String,
+    #[serde(default, alias = "char_persona")]
+    pub description: String,
+    #[serde(default)]
+    pub personality: String,
+    #[serde(default, alias = "world_scenario")]
+    pub scenario: String,
+    #[serde(default, alias = "char_greeting")]
+    pub first_mes: String,
+    #[serde(default, alias = "example_dialogue")]
+    pub mes_example: String,
+    #[serde(default)]
+    pub system_prompt: String,
+    /// the world info of V2 cards, the lorebook when there is no lorebook file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub character_book: Option<Value>,
+}
+
+impl Character {
+    /// replaces the {{char}} and {{user}} placeholders used in cards
+    fn fill(&self, text: &str, your_name: &str) -> String {
+        text.replace("{{char}}", &self.name)
+            .replace("<BOT>", &self.name)
+            .replace("{{user}}", your_name)
+            .replace("<USER>", your_name)
+    }
+
+    /// the character definition that goes in front of the chat
+    pub fn context(&self, your_name: &str) -> String {
+        let mut context = String::new();
+        if !self.system_prompt.is_empty() {
+            context += &format!("{}\n", self.system_prompt);
+        }
+        if !self.description.is_empty() {
+            context += &format!("{}'s Persona: {}\n", self.name, self.description);
+        }
+        if !self.personality.is_empty() {
+            context += &format!("Personality: {}\n", self.personality);
+        }
+        if !self.scenario.is_empty() {
+            context += &format!("Scenario: {}\n", self.scenario);
+        }
+        if !self.mes_example.is_empty() {
+            for example in self.mes_example.split("<START>") {
+                if !example.trim().is_empty() {
+                    context += &format!("<START>\n{}\n", example.trim());
+                }
+            }
+        }
+        self.fill(&context, your_name)
+    }
+
+    pub fn greeting(&self, your_name: &str) -> String {
+        self.fill(&self.first_mes, your_name)
+    }
+}
+
+fn card_path() -> String {
+    get_ini_value("chat_ai", "card").unwrap_or("./config/character.json".to_string())
+}
+
+/// the character card the bot uses, if there is one
+pub fn load_character() -> Option<Character> {
+    let data = fs::read(card_path()).ok()?;
+    match parse_card(&data) {
+        Ok(character) => Some(character),
+        Err(e) => {
+            log::error!("could not read character card {}: {}", card_path(), e);
+            None
+        }
+    }
+}
+
+/// stores the card as the character the bot uses from now on
+pub fn save_character(
+    character: &Character,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fs::write(card_path(), serde_json::to_string_pretty(character)?)?;
+    Ok(())
+}
+
+/// reads a V1 or V2 card from json or from a png with a chara tEXt or iTXt chunk
+pub fn parse_card(data: &[u8]) -> Result<Character, Box<dyn std::error::Error + Send + Sync>> {
+    let json = if data.starts_with(&PNG_SIGNATURE) {
+        let encoded = find_png_text_chunk(data, "chara").ok_or("no chara chunk in png")?;
+        general_purpose::STANDARD.decode(encoded.trim())?
+    } else {
+        data.to_vec()
+    };
+    let value: Value = serde_json::from_slice(&json)?;
+    // V2 cards keep everything inside data
+    let character: Character = match value.get("data") {
+        Some(data) if data.is_object() => serde_json::from_value(data.clone())?,
+        _ => serde_json::from_value(value)?,
+    };
+    if character.name.is_empty() {
+        return Err("character card has no name".into());
+    }
+    Ok(character)
+}
+
+fn find_png_text_chunk(data: &[u8], keyword: &str) -> Option<String> {
+    let mut position = PNG_SIGNATURE.len();
+    while position + 8 <= data.len() {
+        let length = u32::from_be_bytes(data[position..position + 4].try_into().ok()?) as usize;
+        let kind = &data[position + 4..position + 8];
+        let start = position + 8;
+        let end = start.checked_add(length)?;
+        if end > data.len() {
+            return None;
+        }
+        if kind == b"tEXt" || kind == b"iTXt" {
+            let chunk = &data[start..end];
+            if let Some(separator) = chunk.iter().position(|b| *b == 0) {
+                if &chunk[..separator] == keyword.as_bytes() {
+                    let text = &chunk[separator + 1..];
+                    if kind == b"tEXt" {
+                        return Some(String::from_utf8_lossy(text).to_string());
+                    }
+                    return international_text(text);
+                }
+            }
+        }
+        if kind == b"IEND" {
+            return None;
+        }
+        // skip the data and the crc
+        position = end + 4;
+    }
+    None
+}
+
+/// the text of an iTXt chunk after its keyword: compression flag and method,
+/// language tag, translated keyword and then the text
+fn international_text(chunk: &[u8]) -> Option<String> {
+    if chunk.len() < 2 {
+        return None;
+    }
+    if chunk[0] != 0 {
+        log::error!("compressed iTXt chunks are not supported");
+        return None;
+    }
+    let mut rest = &chunk[2..];
+    for _ in 0..2 {
+        let separator = rest.iter().position(|b| *b == 0)?;
+        rest = &rest[separator + 1..];
+    }
+    Some(String::from_utf8_lossy(rest).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a png with just the chunks the card reader looks at, crcs are not checked
+    fn png(chunks: &[(&[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        for (kind, content) in chunks {
+            data.extend((content.len() as u32).to_be_bytes());
+            data.extend(*kind);
+            data.extend(content);
+            data.extend([0; 4]);
+        }
+        data
+    }
+
+    fn chara(card: &str) -> Vec<u8> {
+        general_purpose::STANDARD.encode(card).into_bytes()
+    }
+
+    #[test]
+    fn cards_are_read_from_the_chara_chunk_of_a_png() {
+        let card = r#"{"name": "Aqua"}"#;
+        let header: (&[u8], Vec<u8>) = (b"IHDR", vec![0; 13]);
+        let end: (&[u8], Vec<u8>) = (b"IEND", vec![]);
+
+        let text = [b"chara\0".to_vec(), chara(card)].concat();
+        let data = png(&[header.clone(), (b"tEXt", text), end.clone()]);
+        assert_eq!(parse_card(&data).unwrap().name, "Aqua");
+
+        let international = [b"chara\0\0\0en\0\0".to_vec(), chara(card)].concat();
+        let data = png(&[header.clone(), (b"iTXt", international), end.clone()]);
+        assert_eq!(parse_card(&data).unwrap().name, "Aqua");
+
+        let other = [b"comment\0".to_vec(), chara(card)].concat();
+        let data = png(&[header, (b"tEXt", other), end]);
+        assert!(parse_card(&data).is_err());
+    }
+
+    #[test]
+    fn v1_and_v2_cards_give_the_same_character() {
+        let v1 = r#"{
+            "char_name": "Aqua",
+            "char_persona": "a goddess",
+            "world_scenario": "a guild hall",
+            "char_greeting": "hi {{user}}",
+            "example_dialogue": "<START>{{char}}: hello"
+        }"#;
+        let v2 = r#"{
+            "spec": "chara_card_v2",
+            "data": {
+                "name": "Aqua",
+                "description": "a goddess",
+                "scenario": "a guild hall",
+                "first_mes": "hi {{user}}",
+                "mes_example": "<START>{{char}}: hello",
+                "character_book": { "entries": [{ "keys": ["axis"], "content": "her church" }] }
+            }
+        }"#;
+        let v1 = parse_card(v1.as_bytes()).unwrap();
+        let v2 = parse_card(v2.as_bytes()).unwrap();
+
+        assert_eq!(v1.context("Kazuma"), v2.context("Kazuma"));
+        assert_eq!(
+            v1.context("Kazuma"),
+            "Aqua's Persona: a goddess\nScenario: a guild hall\n<START>\nAqua: hello\n"
+        );
+        assert_eq!(v2.greeting("Kazuma"), "hi Kazuma");
+        assert!(v1.character_book.is_none());
+        assert_eq!(
+            v2.character_book.unwrap()["entries"][0]["content"],
+            "her church"
+        );
+    }
+}
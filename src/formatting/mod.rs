@@ -0,0 +1,260 @@
+use regex::Regex;
+use teloxide::types::ParseMode;
+use teloxide::utils::{html, markdown};
+
+use crate::config::get_ini_value;
+
+/// the most characters telegram accepts in one message
+pub const TELEGRAM_MAX_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Span {
+    Text(String),
+    Bold(String),
+    Italic(String),
+    Strike(String),
+    Code(String),
+    Pre(String),
+    Link { text: String, url: String },
+}
+
+/// the parse mode replies are sent with, None means plain text
+pub fn parse_mode() -> Option<ParseMode> {
+    match get_ini_value("telegram", "parse_mode")
+        .unwrap_or("html".to_string())
+        .to_lowercase()
+        .as_str()
+    {
+        "markdown" | "markdownv2" => Some(ParseMode::MarkdownV2),
+        "html" => Some(ParseMode::Html),
+        _ => None,
+    }
+}
+
+/// model style markdown, every alternative is one kind of span
+fn markup() -> Regex {
+    Regex::new(
+        r"(?s)```(?:[a-zA-Z0-9_+-]*\n)?(.*?)```|`([^`\n]+)`|\*\*(.+?)\*\*|__(.+?)__|~~(.+?)~~|\*([^*\n]+)\*|\b_([^_\n]+)_\b|\[([^\]\n]+)\]\(([^)\s]+)\)",
+    )
+    .unwrap()
+}
+
+/// splits model style markdown into spans, nested markup is not supported
+fn parse_markdown(text: &str) -> Vec<Span> {
+    let re = markup();
+    let mut spans = vec![];
+    let mut last = 0;
+    for captures in re.captures_iter(text) {
+        let whole = captures.get(0).unwrap();
+        if whole.start() > last {
+            spans.push(Span::Text(text[last..whole.start()].to_string()));
+        }
+        let group = |i: usize| captures.get(i).map(|m| m.as_str().to_string());
+        let span = if let Some(pre) = group(1) {
+            Span::Pre(pre)
+        } else if let Some(code) = group(2) {
+            Span::Code(code)
+        } else if let Some(bold) = group(3).or_else(|| group(4)) {
+            Span::Bold(bold)
+        } else if let Some(strike) = group(5) {
+            Span::Strike(strike)
+        } else if let Some(italic) = group(6).or_else(|| group(7)) {
+            Span::Italic(italic)
+        } else {
+            Span::Link {
+                text: group(8).unwrap_or_default(),
+                url: group(9).unwrap_or_default(),
+            }
+        };
+        spans.push(span);
+        last = whole.end();
+    }
+    if last < text.len() {
+        spans.push(Span::Text(text[last..].to_string()));
+    }
+    spans
+}
+
+pub fn to_html(text: &str) -> String {
+    parse_markdown(text)
+        .into_iter()
+        .map(|span| match span {
+            Span::Text(t) => html::escape(&t),
+            Span::Bold(t) => html::bold(&html::escape(&t)),
+            Span::Italic(t) => html::italic(&html::escape(&t)),
+            Span::Strike(t) => html::strike(&html::escape(&t)),
+            Span::Code(t) => html::code_inline(&t),
+            Span::Pre(t) => html::code_block(&t),
+            // html::link leaves quotes in the url, which would end the attribute
+            Span::Link { text, url } => format!(
+                "<a href=\"{}\">{}</a>",
+                html::escape(&url).replace('"', "&quot;"),
+                html::escape(&text)
+            ),
+        })
+        .collect()
+}
+
+pub fn to_markdown_v2(text: &str) -> String {
+    parse_markdown(text)
+        .into_iter()
+        .map(|span| match span {
+            Span::Text(t) => markdown::escape(&t),
+            Span::Bold(t) => markdown::bold(&markdown::escape(&t)),
+            Span::Italic(t) => markdown::italic(&markdown::escape(&t)),
+            Span::Strike(t) => markdown::strike(&markdown::escape(&t)),
+            Span::Code(t) => markdown::code_inline(&t),
+            Span::Pre(t) => markdown::code_block(&t),
+            // markdown::link escapes ) and ` in the url, telegram wants \ escaped as well
+            Span::Link { text, url } => {
+                markdown::link(&url.replace('\\', r"\\"), &markdown::escape(&text))
+            }
+        })
+        .collect()
+}
+
+/// converts the text for the given parse mode
+pub fn format(text: &str, parse_mode: ParseMode) -> String {
+    match parse_mode {
+        ParseMode::Html => to_html(text),
+        _ => to_markdown_v2(text),
+    }
+}
+
+/// Splits a long reply into parts of at most max_length characters,
+/// preferably on paragraphs, then sentences, then words.
+pub fn split_message(text: &str, max_length: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    for piece in split_pieces(text, max_length) {
+        if current.chars().count() + piece.chars().count() > max_length && !current.is_empty() {
+            parts.push(current.trim().to_string());
+            current = String::new();
+        }
+        current.push_str(&piece);
+    }
+    if !current.trim().is_empty() {
+        parts.push(current.trim().to_string());
+    }
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+/// the text cut after every match of re, except where that would cut through markup
+fn outside_markup<'a>(text: &'a str, re: &Regex) -> Vec<&'a str> {
+    let markup: Vec<_> = markup().find_iter(text).map(|m| m.range()).collect();
+    let mut pieces = vec![];
+    let mut start = 0;
+    for end in re.find_iter(text).map(|m| m.end()) {
+        if markup.iter().any(|span| span.start < end && end < span.end) {
+            continue;
+        }
+        pieces.push(&text[start..end]);
+        start = end;
+    }
+    if start < text.len() {
+        pieces.push(&text[start..]);
+    }
+    pieces
+}
+
+// pieces that are each shorter than max_length, joined they form the text again
+fn split_pieces(text: &str, max_length: usize) -> Vec<String> {
+    let paragraphs = Regex::new(r"(?s).+?(?:\n\n+|$)").unwrap();
+    let sentences = Regex::new(r"(?s).+?(?:[.!?]+\s+|\n|$)").unwrap();
+    let words = Regex::new(r"(?s).+?(?: |$)").unwrap();
+    let mut pieces = vec![];
+    for paragraph in outside_markup(text, &paragraphs) {
+        if paragraph.chars().count() <= max_length {
+            pieces.push(paragraph.to_string());
+            continue;
+        }
+        for sentence in outside_markup(paragraph, &sentences) {
+            if sentence.chars().count() <= max_length {
+                pieces.push(sentence.to_string());
+                continue;
+            }
+            // a single sentence that is too long, split it on words
+            let words = outside_markup(sentence, &words)
+                .into_iter()
+                .flat_map(|word| {
+                    // markup that does not fit in a message at all is split like text
+                    if word.chars().count() > max_length {
+                        word.split_inclusive(' ').collect()
+                    } else {
+                        vec![word]
+                    }
+                });
+            let mut current = String::new();
+            for word in words {
+                if current.chars().count() + word.chars().count() > max_length {
+                    if !current.is_empty() {
+                        pieces.push(current);
+                    }
+                    current = String::new();
+                }
+                if word.chars().count() > max_length {
+                    let chars: Vec<char> = word.chars().collect();
+                    for chunk in chars.chunks(max_length) {
+                        pieces.push(chunk.iter().collect());
+                    }
+                } else {
+                    current.push_str(word);
+                }
+            }
+            if !current.is_empty() {
+                pieces.push(current);
+            }
+        }
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_are_filled_up_to_the_limit() {
+        assert_eq!(split_message("abcd efgh", 9), vec!["abcd efgh"]);
+        assert_eq!(split_message("abcd efgh", 8), vec!["abcd", "efgh"]);
+        assert_eq!(split_message("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(
+            split_message("First one. Second one.\n\nNext part.", 25),
+            vec!["First one. Second one.", "Next part."]
+        );
+    }
+
+    #[test]
+    fn markup_is_not_cut_in_two() {
+        let parts = split_message("some words **bold words here** end", 20);
+        assert_eq!(parts, vec!["some words", "**bold words here**", "end"]);
+        assert_eq!(to_html(&parts[1]), "<b>bold words here</b>");
+        assert_eq!(
+            split_message("see [the docs page](http://e.com) now", 30),
+            vec!["see", "[the docs page](http://e.com)", "now"]
+        );
+        assert_eq!(
+            split_message("```\nfn a() {}\n\nfn b() {}\n```\n\nafter", 30),
+            vec!["```\nfn a() {}\n\nfn b() {}\n```", "after"]
+        );
+    }
+
+    #[test]
+    fn html_escapes_text_and_links() {
+        assert_eq!(
+            to_html("1 < 2 & [a<b](http://e.com/?q=\"x\"&y=1)"),
+            "1 &lt; 2 &amp; <a href=\"http://e.com/?q=&quot;x&quot;&amp;y=1\">a&lt;b</a>"
+        );
+        assert_eq!(to_html("**<b>**"), "<b>&lt;b&gt;</b>");
+    }
+
+    #[test]
+    fn markdown_v2_escapes_text_and_links() {
+        assert_eq!(
+            to_markdown_v2("[a_b](http://e.com/a\\b) costs 1.5"),
+            "[a\\_b](http://e.com/a\\\\b) costs 1\\.5"
+        );
+        assert_eq!(to_markdown_v2("`a_b` *c.d*"), "`a_b` _c\\.d_");
+    }
+}
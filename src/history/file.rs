@@ -1,26 +1,55 @@
+use std::collections::BTreeMap;
 use std::io::Write;
+use std::sync::{Arc, Mutex};
 use std::{fs::File, io::Read};
 
+use chrono::{DateTime, Utc};
 use oobabooga_rs::History;
+use tokio::sync::OwnedMutexGuard;
 
 use crate::config::get_ini_value;
+
+static LOCKS: Mutex<BTreeMap<String, Arc<tokio::sync::Mutex<()>>>> = Mutex::new(BTreeMap::new());
+
+/// file name for the character, conversations other than the default one
+/// (e.g. a discord channel) get their own file
+fn file_name(prefix: &str, extension: &str, conversation: Option<&str>) -> String {
+    let character = get_ini_value("chat_ai", "character").unwrap();
+    match conversation {
+        Some(conversation) => {
+            // conversation names can come from api clients, keep them inside the directory
+            let conversation: String = conversation
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+                .collect();
+            format!("{}_{}_{}.{}", prefix, character, conversation, extension)
+        }
+        None => format!("{}_{}.{}", prefix, character, extension),
+    }
+}
+/// Held while a message is answered, so messages from telegram, discord and the api
+/// to the same history do not read it at once and overwrite each others reply.
+pub async fn lock(conversation: Option<&str>) -> OwnedMutexGuard<()> {
+    let lock = LOCKS
+        .lock()
+        .unwrap()
+        .entry(file_name("history", "json", conversation))
+        .or_default()
+        .clone();
+    lock.lock_owned().await
+}
 pub fn write_history_to_file(
     history: &History,
+    conversation: Option<&str>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     log::info!("Writing history to file");
     let json_data = serde_json::to_string(&history)?;
-    let mut file = File::create(format!(
-        "history_{}.json",
-        get_ini_value("chat_ai", "character").unwrap()
-    ))?;
+    let mut file = File::create(file_name("history", "json", conversation))?;
     file.write_all(json_data.as_bytes())?;
     Ok(())
 }
-pub fn read_json_from_file() -> Option<History> {
-    let file = File::open(format!(
-        "history_{}.json",
-        get_ini_value("chat_ai", "character").unwrap()
-    ));
+pub fn read_json_from_file(conversation: Option<&str>) -> Option<History> {
+    let file = File::open(file_name("history", "json", conversation));
     if let Ok(mut f) = file {
         let mut json_data = String::new();
         let res = f.read_to_string(&mut json_data);
@@ -45,3 +74,53 @@ pub fn read_json_from_file() -> Option<History> {
         })
     }
 }
+/// when the user sent the previous message, used to notice long absences
+pub fn read_last_message_time(conversation: Option<&str>) -> Option<DateTime<Utc>> {
+    let time = std::fs::read_to_string(file_name("last_message", "txt", conversation)).ok()?;
+    DateTime::parse_from_rfc3339(time.trim())
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+pub fn write_last_message_time(
+    time: DateTime<Utc>,
+    conversation: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    std::fs::write(
+        file_name("last_message", "txt", conversation),
+        time.to_rfc3339(),
+    )?;
+    Ok(())
+}
+/// the scene of the conversation as json, see ai::scene
+pub fn read_scene(conversation: Option<&str>) -> Option<String> {
+    std::fs::read_to_string(file_name("scene", "json", conversation)).ok()
+}
+pub fn write_scene(
+    scene: &str,
+    conversation: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    std::fs::write(file_name("scene", "json", conversation), scene)?;
+    Ok(())
+}
+/// the last picture she sent or got, what /upscale and the other image tools use without a photo
+pub fn read_last_image(conversation: Option<&str>) -> Option<Vec<u8>> {
+    std::fs::read(file_name("last_image", "png", conversation)).ok()
+}
+pub fn write_last_image(
+    image: &[u8],
+    conversation: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    std::fs::write(file_name("last_image", "png", conversation), image)?;
+    Ok(())
+}
+/// the prompt of the last picture, shown by /lastprompt
+pub fn read_last_prompt(conversation: Option<&str>) -> Option<String> {
+    std::fs::read_to_string(file_name("last_prompt", "txt", conversation)).ok()
+}
+pub fn write_last_prompt(
+    prompt: &str,
+    conversation: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    std::fs::write(file_name("last_prompt", "txt", conversation), prompt)?;
+    Ok(())
+}
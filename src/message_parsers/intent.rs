@@ -0,0 +1,156 @@
+use std::sync::Mutex;
+
+use ini::Ini;
+
+use crate::config::get_ini_value;
+use crate::modules::database::{vectorize, vectorize_all};
+use crate::trace;
+
+/// embeddings of the example phrases, computed once on first use
+static INTENTS: Mutex<Option<Vec<Intent>>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+struct Intent {
+    name: String,
+    examples: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IntentMatch {
+    pub name: String,
+    pub score: f32,
+}
+
+pub fn intents_enabled() -> bool {
+    get_ini_value("intent", "enabled").unwrap_or_default() == "true"
+}
+
+pub fn threshold() -> f32 {
+    get_ini_value("intent", "threshold")
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(0.5)
+}
+
+async fn load_intents() -> Vec<Intent> {
+    if let Some(intents) = INTENTS.lock().unwrap().as_ref() {
+        return intents.clone();
+    }
+    let file = get_ini_value("intent", "file").unwrap_or("./config/intents.ini".to_string());
+    let conf = match Ini::load_from_file(&file) {
+        Ok(conf) => conf,
+        Err(e) => {
+            log::error!("could not load intents from {}: {:?}", file, e);
+            return vec![];
+        }
+    };
+    let mut intents = vec![];
+    for (section, properties) in conf.iter() {
+        let name = match section {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let phrases: Vec<String> = properties
+            .get_all("example")
+            .map(|e| e.to_string())
+            .collect();
+        match vectorize_all(phrases).await {
+            Ok(examples) => intents.push(Intent { name, examples }),
+            Err(e) => log::error!("could not vectorize examples of {}: {:?}", name, e),
+        }
+    }
+    log::info!("loaded {} intents", intents.len());
+    *INTENTS.lock().unwrap() = Some(intents.clone());
+    intents
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// the intent whose example phrase is closest to the message, whatever the score
+pub async fn classify(message: &str) -> Option<IntentMatch> {
+    let intents = load_intents().await;
+    let vector = match vectorize(message.to_string()).await {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("could not vectorize message: {:?}", e);
+            return None;
+        }
+    };
+    best_match(&vector, &intents)
+}
+
+fn best_match(vector: &[f32], intents: &[Intent]) -> Option<IntentMatch> {
+    let mut best: Option<IntentMatch> = None;
+    for intent in intents {
+        for example in &intent.examples {
+            let score = cosine_similarity(vector, example);
+            let better = match &best {
+                Some(b) => score > b.score,
+                None => true,
+            };
+            if better {
+                best = Some(IntentMatch {
+                    name: intent.name.clone(),
+                    score,
+                });
+            }
+        }
+    }
+    best
+}
+
+/// the name of the intent of the message if it scores above the threshold
+pub async fn detect(message: &str) -> Option<String> {
+    trace::exchange("intent", message.into(), detect_intent(message)).await
+}
+
+async fn detect_intent(message: &str) -> Option<String> {
+    let best = classify(message).await?;
+    log::info!("intent: {} score: {}", best.name, best.score);
+    if best.score >= threshold() {
+        Some(best.name)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intents() -> Vec<Intent> {
+        vec![
+            Intent {
+                name: "weather".to_string(),
+                examples: vec![vec![1.0, 0.0, 0.0], vec![0.8, 0.6, 0.0]],
+            },
+            Intent {
+                name: "picture".to_string(),
+                examples: vec![vec![0.0, 0.0, 1.0]],
+            },
+        ]
+    }
+
+    #[test]
+    fn the_closest_example_picks_the_intent() {
+        let best = best_match(&[0.7, 0.7, 0.1], &intents()).unwrap();
+        assert_eq!(best.name, "weather");
+        assert!(best.score > 0.98 && best.score < 1.0);
+
+        let best = best_match(&[0.1, 0.0, 2.0], &intents()).unwrap();
+        assert_eq!(best.name, "picture");
+    }
+
+    #[test]
+    fn vectors_without_direction_match_nothing() {
+        assert_eq!(cosine_similarity(&[0.0, 0.0, 0.0], &[1.0, 0.0, 0.0]), 0.0);
+        assert_eq!(best_match(&[1.0, 0.0, 0.0], &[]).map(|m| m.name), None);
+    }
+}
@@ -1,14 +1,7 @@
+pub mod intent;
+
 use regex::Regex;
 
-#[allow(dead_code)]
-pub fn is_question_about_time(question: &str) -> bool {
-    let lower_question = question.to_lowercase();
-    lower_question.contains("time")
-        || lower_question.contains("hour")
-        || lower_question.contains("minute")
-        || lower_question.contains("clock")
-        || lower_question.contains("date")
-}
 pub fn is_question_about_appointment(question: &str) -> bool {
     let lower_question = question.to_lowercase();
     lower_question.contains("appointment")
@@ -23,6 +16,31 @@ pub fn user_asked_for_pictures(question: &str) -> bool {
         || lower_question.contains("see you")
         || lower_question.contains("show")
 }
+/// how many pictures the message asks for, e.g. "show me 4 pictures" or "two cute selfies"
+pub fn requested_picture_count(question: &str) -> Option<u32> {
+    let regex = Regex::new(
+        r"\b(\d+|two|three|four|five|six|seven|eight|nine|ten)\s+(?:\w+\s+)?(pictures|photos|images|pics|selfies)\b",
+    )
+    .unwrap();
+    let count = regex
+        .captures(&question.to_lowercase())?
+        .get(1)?
+        .as_str()
+        .to_string();
+    let words = [
+        "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+    ];
+    match words.iter().position(|word| *word == count) {
+        Some(index) => Some(index as u32 + 2),
+        None => count.parse().ok(),
+    }
+}
+/// the caption of a photo asks to change it, e.g. "make this anime style"
+pub fn user_asked_for_edit(caption: &str) -> bool {
+    let regex =
+        Regex::new(r"\b(make|turn|put|change|edit|add|remove|replace|style|into)\b").unwrap();
+    regex.is_match(&caption.to_lowercase())
+}
 pub fn is_question_about_pokemon(question: &str) -> bool {
     let lower_question = question.to_lowercase();
     lower_question.contains("pokemon")
@@ -5,7 +5,14 @@ use rust_bert::pipelines::{
     token_classification::TokenClassificationConfig,
 };
 use serde::Deserialize;
+
+use crate::trace;
+
 pub async fn recognize(input: String) -> Option<Vec<Entity>> {
+    trace::exchange("ner", input.clone().into(), predict(input)).await
+}
+
+async fn predict(input: String) -> Option<Vec<Entity>> {
     let thread = thread::spawn(move || {
         let ner_model = NERModel::new(TokenClassificationConfig::default()).unwrap();
 
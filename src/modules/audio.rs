@@ -1,52 +1,51 @@
+use base64::{engine::general_purpose, Engine as _};
 use reqwest::multipart;
-use tokio::{fs::File, io::AsyncReadExt};
 
-use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use rust_ai::azure::{ssml::Speak, Locale, Speech, VoiceName, SSML};
+use rust_ai::azure::{ssml::Speak, Locale, VoiceName, SSML};
 
-use crate::config::get_ini_value;
+use crate::{config::get_ini_value, trace};
 
-pub async fn extract_audio_from_file() -> Result<String, ()> {
-    let client = reqwest::Client::new();
+/// every spoken reply gets its own file, replies in other chats would overwrite it otherwise
+static NEXT_VOICE: AtomicU64 = AtomicU64::new(0);
+
+/// what whisper hears in a voice message, the audio is kept in memory
+pub async fn extract_text_from_audio(audio: Vec<u8>) -> Result<String, ()> {
+    trace::exchange("whisper", serde_json::Value::Null, transcribe(audio)).await
+}
 
-    // Read the file contents into a Vec<u8>
-    let file = File::open("./out/output_audio.ogg").await;
-    match file {
-        Ok(mut f) => {
-            let mut file_contents = Vec::new();
-            f.read_to_end(&mut file_contents).await.unwrap();
+async fn transcribe(audio: Vec<u8>) -> Result<String, ()> {
+    let client = reqwest::Client::new();
 
-            // Create a multipart form
-            let audio_file = multipart::Part::bytes(file_contents)
-                .mime_str("video/ogg")
-                .unwrap()
-                .file_name("./out/output_audio.ogg");
-            let form = multipart::Form::new().part("audio_file", audio_file);
+    // Create a multipart form
+    let audio_file = multipart::Part::bytes(audio)
+        .mime_str("video/ogg")
+        .unwrap()
+        .file_name("voice.ogg");
+    let form = multipart::Form::new().part("audio_file", audio_file);
 
-            // Send the request
-            let response = client
-                .post(
-                    "http://localhost:9000/asr?task=transcribe&language=en&encode=true&output=txt",
-                )
-                .header("accept", "application/json")
-                .multipart(form)
-                .send()
-                .await;
-            match response {
-                Ok(res) => {
-                    // Print the response status and body
-                    println!("Status: {}", res.status());
-                    let body = res.text().await.unwrap();
-                    println!("Body: {}", body);
+    // Send the request
+    let url = get_ini_value("whisper", "url")
+        .filter(|url| !url.is_empty())
+        .unwrap_or("http://localhost:9000".to_string());
+    let response = client
+        .post(format!(
+            "{}/asr?task=transcribe&language=en&encode=true&output=txt",
+            url
+        ))
+        .header("accept", "application/json")
+        .multipart(form)
+        .send()
+        .await;
+    match response {
+        Ok(res) => {
+            // Print the response status and body
+            println!("Status: {}", res.status());
+            let body = res.text().await.unwrap();
+            println!("Body: {}", body);
 
-                    Ok(body)
-                }
-                Err(e) => {
-                    log::error!("{:?}", e);
-                    Err(())
-                }
-            }
+            Ok(body)
         }
         Err(e) => {
             log::error!("{:?}", e);
@@ -55,39 +54,68 @@ pub async fn extract_audio_from_file() -> Result<String, ()> {
     }
 }
 
-pub async fn generate_voice(string: String) -> Result<(), ()> {
-    std::env::set_var(
-        "RUST_AI_CONFIG",
-        format!(
-            r#"
-openai:
-  api_key: sk-
-  base_endpoint: http://localhost
-azure:
-  speech:
-    key: {}
-    region: {}"#,
-            get_ini_value("azure", "key").unwrap(),
-            get_ini_value("azure", "region").unwrap()
-        ),
+/// speaks the text into a new mp3 file and returns its path, remove it once it is sent
+pub async fn generate_voice(string: String) -> Result<String, ()> {
+    let path = format!(
+        "./out/voice_{}.mp3",
+        NEXT_VOICE.fetch_add(1, Ordering::SeqCst)
     );
+    // the trace keeps the audio, a replay writes it to the file again
+    let audio: Result<String, ()> = trace::exchange("tts", string.clone().into(), async {
+        speak(string)
+            .await
+            .map(|audio| general_purpose::STANDARD.encode(audio))
+    })
+    .await;
+    let audio = general_purpose::STANDARD.decode(audio?).map_err(|e| {
+        log::error!("{:?}", e);
+    })?;
+    match std::fs::write(&path, audio) {
+        Ok(_) => Ok(path),
+        Err(e) => {
+            log::error!("{:?}", e);
+            Err(())
+        }
+    }
+}
+
+async fn speak(string: String) -> Result<Vec<u8>, ()> {
     let ssml =
         SSML::from(Speak::voice_content(VoiceName::en_US_JennyNeural, &string).lang(Locale::en_US));
 
     log::debug!("{}", ssml.to_string());
 
-    let result = Speech::from(ssml).tts().await;
+    // the azure text to speech rest api, url can point somewhere else for testing
+    let url = get_ini_value("azure", "url")
+        .filter(|url| !url.is_empty())
+        .unwrap_or(format!(
+            "https://{}.tts.speech.microsoft.com",
+            get_ini_value("azure", "region").unwrap()
+        ));
+    let response = reqwest::Client::new()
+        .post(format!("{}/cognitiveservices/v1", url))
+        .header(
+            "Ocp-Apim-Subscription-Key",
+            get_ini_value("azure", "key").unwrap(),
+        )
+        .header("Content-Type", "application/ssml+xml")
+        .header(
+            "X-Microsoft-OutputFormat",
+            "audio-24khz-48kbitrate-mono-mp3",
+        )
+        .header("User-Agent", "waifu_bot")
+        .body(ssml.to_string())
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    let result = match response {
+        Ok(response) => response.bytes().await,
+        Err(e) => Err(e),
+    };
     match result {
         Ok(result) => {
-            log::debug!("{:?}", result);
-            let res = std::fs::write(PathBuf::from(r"./out/output.mp3"), result);
-            match res {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    log::error!("{:?}", e);
-                    Err(())
-                }
-            }
+            log::debug!("{:?}", result.len());
+            Ok(result.to_vec())
         }
         Err(e) => {
             log::error!("{:?}", e);
@@ -1,7 +1,8 @@
 use std::io::Error;
 
 use crate::config::get_ini_value;
-use chrono::{DateTime, Datelike, Local, NaiveDateTime, Utc};
+use crate::trace;
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
 use ureq;
 use url;
 fn get_appointments() -> Result<Vec<Appointment>, Error> {
@@ -232,9 +233,25 @@ fn parse_recurring_event(data: &str) -> Option<RecurringEvent> {
 }
 
 pub fn parse_query(mut query: String) -> String {
-    let date = Local::now();
+    // traced, a replay asks for the appointments at the time it was recorded
+    let now = trace::exchange_sync("clock", serde_json::Value::Null, Utc::now);
+    let date = crate::modules::time::local(now);
 
-    let appointments_res = get_all_appointments_on_date(Utc::now());
+    if let Some(appointment_text) = get_appointments_text(now) {
+        query = format!(
+            "{} \n {} can use the info provided in the || \n current time: {} \n appointments date {} \n user appointments today: \n {} \n ",
+            query,get_ini_value("chat_ai", "character").unwrap(),convert_24_to_12_hour(&date.format("%H:%M").to_string()), now.format("%Y-%m-%d"),appointment_text);
+    }
+
+    query
+}
+fn get_appointments_text(date: DateTime<Utc>) -> Option<String> {
+    trace::exchange_sync("calendar", date.to_rfc3339().into(), || {
+        format_appointments(date)
+    })
+}
+fn format_appointments(date: DateTime<Utc>) -> Option<String> {
+    let appointments_res = get_all_appointments_on_date(date);
     match appointments_res {
         Ok(appointments) => {
             let mut appointment_text = "".to_string();
@@ -250,15 +267,17 @@ pub fn parse_query(mut query: String) -> String {
             if appointment_text.is_empty() {
                 appointment_text = "No appointments today".to_string();
             }
-
-            query = format!(
-            "{} \n {} can use the info provided in the || \n current time: {} \n appointments date {} \n user appointments today: \n {} \n ",
-            query,get_ini_value("chat_ai", "character").unwrap(),convert_24_to_12_hour(&date.format("%H:%M").to_string()), Utc::now().format("%Y-%m-%d"),appointment_text);
+            Some(appointment_text)
         }
-        Err(_) => {}
+        Err(_) => None,
+    }
+}
+/// the appointments of a day in a form the ai can read
+pub fn appointments_to_string(date: DateTime<Utc>) -> String {
+    match get_appointments_text(date) {
+        Some(text) => format!("appointments on {}: \n {}", date.format("%Y-%m-%d"), text),
+        None => "could not read the calendar".to_string(),
     }
-
-    query
 }
 fn convert_24_to_12_hour(time_str: &str) -> String {
     let parts: Vec<&str> = time_str.split(':').collect();
@@ -1,12 +1,18 @@
 use chrono::{DateTime, Utc};
 use reqwest::header;
 use rust_bert::{
-    pipelines::sentence_embeddings::{SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType},
+    pipelines::sentence_embeddings::{
+        SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
+    },
     RustBertError,
 };
 use serde::{Deserialize, Serialize};
 
-use std::thread;
+use std::sync::Mutex;
+
+/// the embeddings model, loaded on first use and kept since loading takes seconds
+static MODEL: Mutex<Option<SentenceEmbeddingsModel>> = Mutex::new(None);
+
 pub async fn send_string_to_server(
     string: String,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
@@ -22,22 +28,27 @@ pub async fn get_simmilar(
     get(res).await
 }
 pub async fn vectorize(input: String) -> Result<Vec<f32>, RustBertError> {
-    let thread = thread::spawn(move || {
-        let model = SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL12V2)
-            .create_model()?;
-
-        let sentences = [input];
-
-        
-        model.encode(&sentences)
-    });
-
-    let res = thread.join().unwrap();
+    let res = vectorize_all(vec![input]).await;
     match res {
         Ok(res) => Ok(res[0].clone()),
         Err(e) => Err(e),
     }
 }
+/// encodes on a blocking thread, the model would hold up the runtime otherwise
+pub async fn vectorize_all(input: Vec<String>) -> Result<Vec<Vec<f32>>, RustBertError> {
+    tokio::task::spawn_blocking(move || {
+        let mut model = MODEL.lock().unwrap();
+        if model.is_none() {
+            *model = Some(
+                SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL12V2)
+                    .create_model()?,
+            );
+        }
+        model.as_ref().unwrap().encode(&input)
+    })
+    .await
+    .unwrap()
+}
 async fn send(
     id: String,
     vector: Vec<f32>,
@@ -0,0 +1,156 @@
+use std::fs;
+
+use oobabooga_rs::History;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{character, config::get_ini_value};
+
+/// One world info entry, field names follow SillyTavern world info files
+/// and the character book of V2 cards.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Entry {
+    #[serde(default, alias = "key")]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub content: String,
+    /// character book entries can have both, priority wins
+    #[serde(default)]
+    pub priority: Option<i64>,
+    #[serde(default, alias = "order")]
+    pub insertion_order: i64,
+    #[serde(default)]
+    pub disable: bool,
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    /// longer content is cut to about this many tokens
+    #[serde(default)]
+    pub token_budget: Option<usize>,
+}
+fn enabled_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone)]
+pub struct Lorebook {
+    pub entries: Vec<Entry>,
+    pub token_budget: usize,
+}
+
+pub fn lorebook_enabled() -> bool {
+    get_ini_value("lorebook", "enabled").unwrap_or_default() == "true"
+}
+
+/// rough estimate, about four characters per token
+fn count_tokens(text: &str) -> usize {
+    text.chars().count() / 4 + 1
+}
+
+/// cuts the text to about budget tokens, at a word when there is one
+fn truncate(text: &str, budget: usize) -> String {
+    let limit = budget * 4;
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(limit).collect();
+    match cut.rfind(char::is_whitespace) {
+        Some(end) if end > 0 => cut[..end].trim_end().to_string(),
+        _ => cut,
+    }
+}
+
+pub fn load_lorebook() -> Option<Lorebook> {
+    let file = get_ini_value("lorebook", "file").unwrap_or("./config/lorebook.json".to_string());
+    let value: Value = match fs::read_to_string(&file) {
+        Ok(data) => match serde_json::from_str(&data) {
+            Ok(value) => value,
+            Err(e) => {
+                log::error!("could not parse lorebook {}: {:?}", file, e);
+                return None;
+            }
+        },
+        // without a lorebook file the character book of the card is used
+        Err(e) => match character::load_character().and_then(|c| c.character_book) {
+            Some(book) => book,
+            None => {
+                log::error!("could not read lorebook {}: {:?}", file, e);
+                return None;
+            }
+        },
+    };
+    // SillyTavern stores entries as a map, character books as a list
+    let raw_entries: Vec<Value> = match &value["entries"] {
+        Value::Array(entries) => entries.clone(),
+        Value::Object(entries) => entries.values().cloned().collect(),
+        _ => vec![],
+    };
+    let entries = raw_entries
+        .into_iter()
+        .filter_map(|e| serde_json::from_value::<Entry>(e).ok())
+        .filter(|e| e.enabled && !e.disable && !e.content.is_empty())
+        .collect();
+    // the budget of the lorebook itself wins over the one of the config
+    let token_budget = value["token_budget"]
+        .as_u64()
+        .map(|b| b as usize)
+        .or(get_ini_value("lorebook", "token_budget").and_then(|b| b.parse().ok()))
+        .unwrap_or(400);
+    Some(Lorebook {
+        entries,
+        token_budget,
+    })
+}
+
+/// the entries whose keywords are mentioned in the recent chat, highest priority first
+pub fn world_info(history: &History, message_text: &str) -> Option<String> {
+    let lorebook = load_lorebook()?;
+    let scan_depth: usize = get_ini_value("lorebook", "scan_depth")
+        .and_then(|d| d.parse().ok())
+        .unwrap_or(2);
+    let mut recent = message_text.to_lowercase();
+    for exchange in history.internal.iter().rev().take(scan_depth) {
+        recent += &format!("\n{}", exchange.join("\n").to_lowercase());
+    }
+
+    let mut triggered: Vec<&Entry> = lorebook
+        .entries
+        .iter()
+        .filter(|entry| {
+            entry.keys.iter().any(|key| {
+                let key = key.trim().to_lowercase();
+                !key.is_empty()
+                    && Regex::new(&format!(r"\b{}\b", regex::escape(&key)))
+                        .map(|re| re.is_match(&recent))
+                        .unwrap_or(false)
+            })
+        })
+        .collect();
+    triggered
+        .sort_by_key(|entry| std::cmp::Reverse(entry.priority.unwrap_or(entry.insertion_order)));
+
+    let mut used = 0;
+    let mut info = vec![];
+    for entry in triggered {
+        let content = match entry.token_budget {
+            Some(budget) => truncate(entry.content.trim(), budget),
+            None => entry.content.trim().to_string(),
+        };
+        let tokens = count_tokens(&content);
+        if used + tokens > lorebook.token_budget {
+            continue;
+        }
+        used += tokens;
+        info.push(content);
+    }
+    log::debug!(
+        "world info uses {} of {} tokens",
+        used,
+        lorebook.token_budget
+    );
+    if info.is_empty() {
+        None
+    } else {
+        Some(info.join("\n"))
+    }
+}
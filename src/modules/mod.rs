@@ -2,5 +2,7 @@ pub mod EntityRecognition;
 pub mod audio;
 pub mod calendar;
 pub mod database;
+pub mod lorebook;
 pub mod pokeapi;
+pub mod time;
 pub mod weather;
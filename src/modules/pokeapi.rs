@@ -6,7 +6,13 @@ use std::{
 
 use rustemon::model::pokemon::Pokemon;
 
+use crate::trace;
+
 pub async fn get_pokemon(name: &str) -> Option<Pokemon> {
+    trace::exchange("pokemon", name.into(), fetch_pokemon(name)).await
+}
+
+async fn fetch_pokemon(name: &str) -> Option<Pokemon> {
     let rustemon_client = rustemon::client::RustemonClient::default();
     let pokemon = rustemon::pokemon::pokemon::get_by_name(name, &rustemon_client).await;
     match pokemon {
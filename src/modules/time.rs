@@ -0,0 +1,84 @@
+use chrono::{DateTime, FixedOffset, Local, Offset, Utc};
+use chrono_tz::Tz;
+
+use crate::{config::get_ini_value, trace};
+
+pub fn time_enabled() -> bool {
+    get_ini_value("time", "enabled").unwrap_or("true".to_string()) == "true"
+}
+
+/// the configured timezone, None means the timezone of the system
+fn timezone() -> Option<Tz> {
+    let name = get_ini_value("time", "timezone").unwrap_or_default();
+    if name.is_empty() {
+        return None;
+    }
+    match name.parse::<Tz>() {
+        Ok(tz) => Some(tz),
+        Err(e) => {
+            log::error!("unknown timezone {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// the current time in the configured timezone
+pub fn now() -> DateTime<FixedOffset> {
+    local(Utc::now())
+}
+
+/// a time in the configured timezone
+pub fn local(time: DateTime<Utc>) -> DateTime<FixedOffset> {
+    match timezone() {
+        Some(tz) => {
+            let local = time.with_timezone(&tz);
+            local.with_timezone(&local.offset().fix())
+        }
+        None => time.with_timezone(&Local).into(),
+    }
+}
+
+fn humanize(duration: chrono::Duration) -> String {
+    let plural = |n: i64, unit: &str| {
+        if n == 1 {
+            format!("1 {}", unit)
+        } else {
+            format!("{} {}s", n, unit)
+        }
+    };
+    if duration.num_days() > 0 {
+        plural(duration.num_days(), "day")
+    } else if duration.num_hours() > 0 {
+        plural(duration.num_hours(), "hour")
+    } else {
+        plural(duration.num_minutes(), "minute")
+    }
+}
+
+/// The current date and time for the prompt, and how long ago the user
+/// wrote before if that was more than an hour ago.
+pub fn time_context(last_message: Option<DateTime<Utc>>) -> Option<String> {
+    let request = serde_json::to_value(last_message).unwrap_or_default();
+    trace::exchange_sync("time", request, || current_time_context(last_message))
+}
+
+fn current_time_context(last_message: Option<DateTime<Utc>>) -> Option<String> {
+    if !time_enabled() {
+        return None;
+    }
+    let now = now();
+    let mut context = format!(
+        "current date: {}, current time: {}",
+        now.format("%A %-d %B %Y"),
+        now.format("%I:%M %p")
+    );
+    let since_last_message =
+        get_ini_value("time", "since_last_message").unwrap_or("true".to_string()) == "true";
+    if let (true, Some(last_message)) = (since_last_message, last_message) {
+        let gap = Utc::now() - last_message;
+        if gap.num_hours() >= 1 {
+            context += &format!(", the user's last message was {} ago", humanize(gap));
+        }
+    }
+    Some(context)
+}
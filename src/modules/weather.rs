@@ -1,38 +1,70 @@
-use crate::config::get_ini_value;
+use serde::Deserialize;
+
+use crate::{config::get_ini_value, trace};
+
+#[derive(Deserialize, Debug)]
+struct CurrentWeather {
+    name: String,
+    main: Main,
+    weather: Vec<Weather>,
+}
+#[derive(Deserialize, Debug)]
+struct Main {
+    temp: f64,
+}
+#[derive(Deserialize, Debug)]
+struct Weather {
+    description: String,
+}
 
 pub async fn get_weather(city: String) -> Option<String> {
-    let mut options = openweathermap_client::ClientOptions::default();
-    options.api_key = get_ini_value("openweather", "token").unwrap();
-    let client = openweathermap_client::Client::new(options);
-    match client {
-        Ok(client) => {
-            let weather = client
-                .fetch_weather(&openweathermap_client::models::City::new(
-                    city.as_str(),
-                    "NL",
-                ))
-                .await;
-            match weather {
-                Ok(weather) => return Some(weather_to_string(weather)),
-                ApiCallError => {
-                    log::error!("Error: {:?}", ApiCallError);
-                }
-            }
-        }
+    trace::exchange("weather", city.clone().into(), fetch_weather(city)).await
+}
+
+async fn fetch_weather(city: String) -> Option<String> {
+    // the openweathermap current weather api, url can point somewhere else for testing
+    let url = get_ini_value("openweather", "url")
+        .filter(|url| !url.is_empty())
+        .unwrap_or("https://api.openweathermap.org".to_string());
+    let response = reqwest::Client::new()
+        .get(format!("{}/data/2.5/weather", url))
+        .query(&[
+            ("q", query(&city)),
+            ("appid", get_ini_value("openweather", "token").unwrap()),
+            ("units", "metric".to_string()),
+        ])
+        .send()
+        .await;
+    let weather = match response {
+        Ok(response) => response.json::<CurrentWeather>().await,
+        Err(e) => Err(e),
+    };
+    match weather {
+        Ok(weather) => Some(weather_to_string(weather)),
         Err(e) => {
-            println!("Error: {}", e);
+            log::error!("Error: {:?}", e);
+            None
         }
-    };
-    None
+    }
+}
+
+/// the city with the country code of `[openweather] country`, without one openweathermap guesses
+fn query(city: &str) -> String {
+    match get_ini_value("openweather", "country") {
+        Some(country) if !country.trim().is_empty() => format!("{},{}", city, country.trim()),
+        _ => city.to_string(),
+    }
 }
 
-fn weather_to_string(weather: openweathermap_client::models::CurrentWeather) -> String {
+fn weather_to_string(weather: CurrentWeather) -> String {
     let mut msg = "".to_string();
     msg += &format!(
         "current temprature in {} is {}°C, ",
         weather.name, weather.main.temp
     );
-    msg += &format!("it is {} now, ", weather.weather[0].description);
+    if let Some(current) = weather.weather.first() {
+        msg += &format!("it is {} now, ", current.description);
+    }
 
     msg
 }
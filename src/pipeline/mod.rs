@@ -0,0 +1,878 @@
+pub mod progress;
+pub mod queue;
+pub mod records;
+
+use chrono::Utc;
+use oobabooga_rs::History;
+
+use crate::{
+    ai, character,
+    config::get_ini_value,
+    history::{self, file::write_history_to_file},
+    message_parsers::{
+        intent::{classify, detect, intents_enabled, threshold as intent_threshold},
+        is_question_about_appointment, is_question_about_pokemon, is_question_about_weather,
+        requested_picture_count, user_asked_for_pictures,
+    },
+    modules::{
+        self,
+        audio::{extract_text_from_audio, generate_voice},
+        pokeapi::PokemonEx,
+        EntityRecognition,
+    },
+    trace,
+    transport::{Picture, Transport},
+};
+
+/// Handles the slash commands every frontend shares.
+/// Returns false when the command is unknown, so the frontend can handle it.
+pub async fn handle_command(
+    transport: &dyn Transport,
+    text: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let conversation = transport.conversation();
+    if text == "/reset" {
+        write_history_to_file(
+            &History {
+                internal: vec![],
+                visible: vec![],
+            },
+            conversation.as_deref(),
+        )?;
+        transport.send_text("History has been reset.").await?;
+        if let Some(character) = character::load_character() {
+            let greeting = character.greeting(&get_ini_value("chat_ai", "your_name").unwrap());
+            if !greeting.is_empty() {
+                transport.send_text(&greeting).await?;
+            }
+        }
+    } else if text == "/undo" {
+        if let Some(mut h) = history::file::read_json_from_file(conversation.as_deref()) {
+            write_history_to_file(&h.undo(), conversation.as_deref())?;
+            transport
+                .send_text(&format!(
+                    "undo Sucessful. \n last message: {}",
+                    h.last().unwrap_or_default()
+                ))
+                .await?;
+        }
+    } else if text == "/sticker" {
+        transport
+            .send_sticker("/home/yvonne/Documents/GitHub/teloxide/stickers/Embarrasment.png")
+            .await?;
+    } else if text == "/cancel" {
+        let reply = match queue::cancel(transport).await {
+            queue::Cancelled::Queued => "The picture has been taken out of the queue.",
+            queue::Cancelled::Running => "The picture has been stopped.",
+            queue::Cancelled::Nothing => "There is no picture to cancel.",
+        };
+        transport.send_text(reply).await?;
+    } else if text == "/lastprompt" {
+        let prompt = history::file::read_last_prompt(conversation.as_deref())
+            .unwrap_or("no picture has been generated yet".to_string());
+        transport.send_text(&prompt).await?;
+    } else if ai::image_tools::Tool::from_command(text).is_some() {
+        image_tool(transport, text, None).await?;
+    } else if let Some(request) = text.strip_prefix("/scene") {
+        let reply = scene(conversation.as_deref(), request.trim()).await;
+        transport.send_text(&reply).await?;
+    } else if let Some(request) = text.strip_prefix("/imagine") {
+        imagine(transport, request).await?;
+    } else if let Some(query) = text.strip_prefix("/intent ") {
+        let reply = match classify(query).await {
+            Some(intent) => format!(
+                "intent: {} score: {:.3} (threshold {})",
+                intent.name,
+                intent.score,
+                intent_threshold()
+            ),
+            None => "no intent found".to_string(),
+        };
+        transport.send_text(&reply).await?;
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// replies to the message, recording a trace of it when tracing is enabled
+pub async fn ai_reply(
+    transport: &dyn Transport,
+    message_text: &str,
+    history: History,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let message = trace::Message::new(message_text, false, &history, transport.conversation());
+    trace::record(message, reply(transport, message_text, history, None)).await
+}
+
+/// Lets the character look at a photo the user sent and react to it. The model
+/// gets what she saw, the visible history a reference to the saved photo.
+pub async fn photo_reply(
+    transport: &dyn Transport,
+    caption: &str,
+    image: &[u8],
+    history: History,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !photo_allowed(transport, image).await? {
+        return Ok(());
+    }
+    let description = match ai::vision::describe_image(image).await {
+        Some(description) => description,
+        None => {
+            transport.send_text("could not look at the photo").await?;
+            return Ok(());
+        }
+    };
+    log::info!("photo: {}", description);
+    let conversation = transport.conversation();
+    let before = history.internal.len();
+    described_photo_reply(transport, caption, &description, history).await?;
+    match ai::vision::save_photo(image) {
+        Ok(path) => {
+            if let Some(mut history) = history::file::read_json_from_file(conversation.as_deref()) {
+                if history.internal.len() > before {
+                    if let Some(input) = history.visible.last_mut().and_then(|e| e.first_mut()) {
+                        *input = format!("[photo {}] {}", path, caption.trim())
+                            .trim()
+                            .to_string();
+                    }
+                    write_history_to_file(&history, conversation.as_deref())?;
+                }
+            }
+        }
+        Err(e) => log::error!("could not save the photo {:?}", e),
+    }
+    Ok(())
+}
+
+/// replies to a photo that has been described, recording a trace of it when tracing is enabled
+pub async fn described_photo_reply(
+    transport: &dyn Transport,
+    caption: &str,
+    description: &str,
+    history: History,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut message = trace::Message::new(caption, false, &history, transport.conversation());
+    message.photo = Some(description.to_string());
+    trace::record(
+        message,
+        reply(transport, caption, history, Some(description)),
+    )
+    .await
+}
+
+/// photo is the description of a photo the user sent along, the message is its caption then
+async fn reply(
+    transport: &dyn Transport,
+    message_text: &str,
+    history: History,
+    photo: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    //create ai client and config
+    let mut chat_config = ai::chat::chat_request(history.clone());
+
+    // remember when the user wrote, so she can notice long absences
+    let conversation = transport.conversation();
+    let last_message = history::file::read_last_message_time(conversation.as_deref());
+    if let Err(e) = history::file::write_last_message_time(Utc::now(), conversation.as_deref()) {
+        log::error!("could not store message time {:?}", e);
+    }
+    let time_context = modules::time::time_context(last_message);
+    // the time is for the prompt only, the history keeps the message
+    let with_time = |input: String| match &time_context {
+        Some(time) => format!("{} | {} |", input, time),
+        None => input,
+    };
+
+    // what the model reads, the caption is still what the user asked
+    let seen = |text: &str| match photo {
+        Some(description) => format!("*sends you a photo: {}* {}", description, text)
+            .trim()
+            .to_string(),
+        None => text.to_string(),
+    };
+
+    // let the model decide which modules to use
+    if ai::tools::tools_enabled() {
+        let (response, results) = match ai::tools::reply_with_tools(
+            &history,
+            &seen(message_text),
+            time_context.as_deref(),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!("{:?}", e);
+                return Err(e);
+            }
+        };
+        send_reply(transport, response).await;
+        for picture in results.into_iter().filter_map(|result| result.picture) {
+            imagine(transport, &picture).await?;
+        }
+        return Ok(());
+    }
+
+    // find out what the user wants, by embeddings or by keywords
+    let intent = if intents_enabled() {
+        detect(message_text).await
+    } else {
+        None
+    };
+    let asked_for = |name: &str, keywords: fn(&str) -> bool| {
+        if intents_enabled() {
+            intent.as_deref() == Some(name)
+        } else {
+            keywords(message_text)
+        }
+    };
+
+    // test if user asked for pictures
+    // a photo of the user is not a request for one of hers
+    if photo.is_none()
+        && asked_for("picture", user_asked_for_pictures)
+        && get_ini_value("sd_ai", "enabled").unwrap() == "true"
+    {
+        // `--ar 2:3 --steps 30` and the like change the settings of this picture only
+        let (message_text, mut image_settings) =
+            match ai::image_settings::parse_overrides(message_text) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    transport.send_text(&e).await?;
+                    return Ok(());
+                }
+            };
+        let status_id = transport.send_status("Generating picture...").await?;
+        // generate a picture
+        // "show me 4 pictures" is a batch, unless --n says otherwise
+        if image_settings.batch_size == 1 {
+            if let Some(count) = requested_picture_count(&message_text) {
+                image_settings.set_batch_size(count);
+            }
+        }
+        // ask ai for a promt.
+
+        let msg = format!(
+            "{}|Describe it in very high detail so the user can see it, then send it to the user|",
+            message_text
+        );
+        log::trace!("{}", msg);
+
+        chat_config.user_input = with_time(msg.clone());
+        let response = ai::chat::get_chat(chat_config).await;
+        match response {
+            Ok(mut res) => {
+                log::info!("ai replied");
+                ai::chat::restore_user_input(&mut res, &msg);
+                let res = ai::postprocess::clean_history(res);
+                match write_history_to_file(&res, conversation.as_deref()) {
+                    Ok(_) => {
+                        log::info!("history written to file")
+                    }
+                    Err(e) => {
+                        log::error!("error writing history to file{:?}", e)
+                    }
+                }
+
+                // turn her description into tags for stable diffusion
+                let description = res.last().unwrap_or_default();
+                let subject = match ai::prompt::subject_from_chat(&res).await {
+                    Some(subject) => subject,
+                    None => message_text.clone(),
+                };
+                // where she is and what she wears, so the picture matches the chat
+                let scene = match ai::scene::scene_enabled() {
+                    true => {
+                        let scene = ai::scene::Scene::read(conversation.as_deref());
+                        let scene = ai::scene::update_from_chat(&res, &scene).await;
+                        scene.write(conversation.as_deref());
+                        Some(scene)
+                    }
+                    false => None,
+                };
+                let prompt = ai::prompt::compose(
+                    &subject,
+                    ai::prompt::shows_character(&message_text, &description),
+                    &image_settings,
+                    scene.as_ref(),
+                );
+                log::info!("image prompt: {}", prompt);
+                remember_prompt(transport, &prompt, &image_settings);
+
+                let job = queue::Job::new(
+                    transport,
+                    "Generating picture...",
+                    prompt,
+                    image_settings,
+                    None,
+                    None,
+                );
+                let img_res = queue::generate(transport, status_id, job).await;
+                match img_res {
+                    Ok(_) => {
+                        log::info!("photo generated");
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                        log::info!("picture cancelled");
+                    }
+                    Err(e) => {
+                        //notify user of error
+                        let res = transport.send_text("could not send image").await;
+                        log::error!("{:?}", e);
+                        match res {
+                            Ok(_) => {
+                                log::info!("user notified of error");
+                            }
+                            Err(e) => {
+                                log::error!("{:?}", e);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("{:?}", e);
+                let res = transport
+                    .send_text("could not contact ai server for image promt")
+                    .await;
+                match res {
+                    Ok(_) => {
+                        log::info!("user notified of error");
+                    }
+                    Err(e) => {
+                        log::error!("{:?}", e);
+                    }
+                }
+            }
+        }
+    } else {
+        let mut message = seen(message_text);
+        //find simmilar
+        // let sim_res = get_simmilar(message.clone()).await;
+        // match sim_res{
+        //     Ok(res) => {
+        //         log::info!("message: {} simmilar found {:?} score: {} ",message_text, res.embedding.id, res.score);
+        //         bot.send_message(chat_id, format!("message: {} simmilar found {:?} score: {}, metadata {} ",message_text, res.embedding.id, res.score, res.embedding.metadata.date)).await;
+        //     }
+
+        //     Err(e) => {
+        //         log::error!("{:?}", e);
+        //     }
+        // }
+        // no image was requested
+        // TODO implement history
+        // TODO implement calendar
+        if asked_for("appointment", is_question_about_appointment)
+            && get_ini_value("calendar", "enabled").unwrap() == "true"
+        {
+            log::info!("asked for appointments");
+            message = modules::calendar::parse_query(message.to_string());
+            log::debug!("appointments parsed {}", message);
+        }
+        if asked_for("weather", is_question_about_weather) {
+            log::info!("asked for weather {}", message_text);
+            // let mut config = huggingface_inference_rs::Config::default();
+            // config.key = get_ini_value("huggingface", "token").unwrap();
+            // let client = huggingface_inference_rs::Client::new(config);
+            // let res = client.get_classifications(message_text.to_owned()).await;
+            let res = EntityRecognition::recognize(message_text.to_owned()).await;
+            match &res {
+                Some(res) => {
+                    let mut first_loc: Vec<&str> = Vec::new();
+
+                    // TODO implement weather module
+                    // if res contains a LOC entity_group
+                    log::info!("res: {:?}", res);
+                    for entity in res {
+                        if entity.label == "LOC" || entity.label == "I-LOC" {
+                            first_loc.push(entity.word.as_ref());
+                        }
+                    }
+                    if first_loc.is_empty() {
+                        for entity in res {
+                            if entity.label == "ORG" {
+                                first_loc.push(entity.word.as_ref());
+                            }
+                        }
+                    }
+                    // if there is a first location
+                    log::info!("first location: {:?}", first_loc);
+                    if !first_loc.is_empty() {
+                        match modules::weather::get_weather(first_loc[0].to_string()).await {
+                            None => {
+                                log::error!("could not get weather");
+                            }
+                            Some(w) => {
+                                message = format!("{} | this is the weather information you can relay it to the user |  {}", message, w);
+                            }
+                        }
+                        log::info!("weather: {:?}", message);
+                    }
+                }
+
+                None => {
+                    log::error!("error: No Place found")
+                }
+            }
+        }
+
+        if asked_for("pokemon", is_question_about_pokemon) {
+            match modules::pokeapi::find_pokemon(message_text) {
+                Some(pokemon) => {
+                    let res = modules::pokeapi::get_pokemon(&pokemon).await;
+                    match res {
+                        Some(pokemon) => message = pokemon.to_ai_string(),
+                        None => {
+                            log::error!("could not get pokemon")
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+        if modules::lorebook::lorebook_enabled() {
+            if let Some(info) = modules::lorebook::world_info(&history, message_text) {
+                log::info!("world info triggered");
+                message = format!(
+                    "{} | background information you know, use it when it fits | {}",
+                    message, info
+                );
+            }
+        }
+        // let out = send_string_to_server(message.clone()).await;
+
+        log::info!("message: {}", message);
+        chat_config.user_input = with_time(message.clone());
+
+        let response = ai::chat::get_chat(chat_config).await;
+        log::info!("response: {:?}", response);
+
+        //send response
+        match response {
+            Ok(mut response) => {
+                ai::chat::restore_user_input(&mut response, &message);
+                send_reply(transport, response).await
+            }
+            Err(e) => {
+                // TODO notify user of error
+                log::error!("{:?}", e);
+
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Changes a photo the user sent as the caption asks, a second image is the inpainting mask.
+pub async fn edit_reply(
+    transport: &dyn Transport,
+    caption: &str,
+    image: &[u8],
+    mask: Option<&[u8]>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !photo_allowed(transport, image).await? {
+        return Ok(());
+    }
+    let (instruction, image_settings) = match ai::image_settings::parse_overrides(caption) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            transport.send_text(&e).await?;
+            return Ok(());
+        }
+    };
+    let status_id = transport.send_status("Editing picture...").await?;
+    let subject = match ai::prompt::subject_from_instruction(&instruction).await {
+        Some(subject) => subject,
+        None => instruction.clone(),
+    };
+    let prompt = ai::prompt::compose(
+        &subject,
+        ai::prompt::shows_character(&instruction, ""),
+        &image_settings,
+        None,
+    );
+    log::info!("img2img prompt: {}", prompt);
+    remember_prompt(transport, &prompt, &image_settings);
+    let job = queue::Job::new(
+        transport,
+        "Editing picture...",
+        prompt,
+        image_settings,
+        Some(image),
+        mask,
+    );
+    match queue::generate(transport, status_id, job).await {
+        Ok(_) => log::info!("photo edited"),
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => log::info!("edit cancelled"),
+        Err(e) => {
+            log::error!("{:?}", e);
+            transport.send_text("could not edit the picture").await?;
+        }
+    }
+    Ok(())
+}
+
+/// Handles a button under a picture, e.g. "reroll:12" generates picture 12 again with another seed.
+pub async fn image_action(
+    transport: &dyn Transport,
+    action: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (name, id) = action.split_once(':').unwrap_or((action, ""));
+    let record = match id.parse().ok().and_then(records::find) {
+        Some(record) => record,
+        None => {
+            transport
+                .send_text("That picture is not kept anymore.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let mut settings = record.job.settings.clone();
+    let status = match name {
+        "prompt" => {
+            transport.send_text(&records::describe(&record)).await?;
+            return Ok(());
+        }
+        "reroll" => {
+            settings.seed = -1;
+            settings.subseed_strength = 0.0;
+            "Generating picture..."
+        }
+        "variation" => {
+            settings.subseed = -1;
+            settings.subseed_strength = ai::image_settings::setting("variation_strength", 0.3);
+            "Generating a variation..."
+        }
+        "upscale" => {
+            settings.upscale(record.job.image.is_some());
+            "Upscaling picture..."
+        }
+        _ => {
+            log::info!("unknown image action {}", action);
+            return Ok(());
+        }
+    };
+    let status_id = transport.send_status(status).await?;
+    let job = record.job.again(transport, status, settings);
+    generate_picture(transport, status_id, job).await
+}
+
+/// `/imagine n=4 a cat in the rain` generates the prompt as it is, without asking the character
+async fn imagine(
+    transport: &dyn Transport,
+    request: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut request = request.trim();
+    let mut count = None;
+    if let Some(rest) = request.strip_prefix("n=") {
+        let (n, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        count = n.parse().ok();
+        request = rest;
+    }
+    let (subject, mut settings) = match ai::image_settings::parse_overrides(request) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            transport.send_text(&e).await?;
+            return Ok(());
+        }
+    };
+    if subject.is_empty() {
+        transport
+            .send_text("usage: /imagine n=4 a cat in the rain")
+            .await?;
+        return Ok(());
+    }
+    if let Some(count) = count {
+        settings.set_batch_size(count);
+    }
+    let status_id = transport.send_status("Generating picture...").await?;
+    let prompt = ai::prompt::compose(
+        &subject,
+        ai::prompt::shows_character(&subject, ""),
+        &settings,
+        None,
+    );
+    log::info!("imagine prompt: {}", prompt);
+    remember_prompt(transport, &prompt, &settings);
+    let job = queue::Job::new(
+        transport,
+        "Generating picture...",
+        prompt,
+        settings,
+        None,
+        None,
+    );
+    generate_picture(transport, status_id, job).await
+}
+
+/// `/scene` shows the scene, `/scene outfit red dress` changes it, `/scene clear` forgets
+/// it and `/scene update` asks the model again from the conversation
+async fn scene(conversation: Option<&str>, request: &str) -> String {
+    let mut scene = ai::scene::Scene::read(conversation);
+    match request {
+        "" => return scene.describe(),
+        "clear" => scene = ai::scene::Scene::default(),
+        "update" => {
+            let history = history::file::read_json_from_file(conversation).unwrap_or(History {
+                internal: vec![],
+                visible: vec![],
+            });
+            scene = ai::scene::update_from_chat(&history, &scene).await;
+        }
+        request => {
+            let (field, value) = request.split_once(' ').unwrap_or((request, ""));
+            if !scene.set(field, value) {
+                return "usage: /scene [location|outfit|time|activity] <value>, /scene clear or /scene update".to_string();
+            }
+        }
+    }
+    scene.write(conversation);
+    scene.describe()
+}
+
+/// false when the safety policy of the chat blocks the photo the user sent, telling them.
+/// An allowed photo is kept for the image tools.
+async fn photo_allowed(
+    transport: &dyn Transport,
+    image: &[u8],
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let chat = queue::owner(transport);
+    if ai::safety::judge(&chat, image, "received").await == ai::safety::Policy::Block {
+        transport
+            .send_text("That photo is not allowed in this chat.")
+            .await?;
+        return Ok(false);
+    }
+    if let Err(e) = history::file::write_last_image(image, transport.conversation().as_deref()) {
+        log::error!("could not keep the photo {:?}", e);
+    }
+    Ok(true)
+}
+
+/// Runs `/upscale`, `/restore_faces`, `/remove_bg` or `/describe` on the photo sent with
+/// the command, or on the last picture of the chat without one. Returns false for other text.
+pub async fn image_tool(
+    transport: &dyn Transport,
+    command: &str,
+    photo: Option<&[u8]>,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let (tool, argument) = match ai::image_tools::Tool::from_command(command) {
+        Some(tool) => tool,
+        None => return Ok(false),
+    };
+    let image = match photo {
+        Some(photo) => {
+            if !photo_allowed(transport, photo).await? {
+                return Ok(true);
+            }
+            photo.to_vec()
+        }
+        None => match history::file::read_last_image(transport.conversation().as_deref()) {
+            Some(image) => image,
+            None => {
+                transport
+                    .send_text(
+                        "Send a photo with the command as caption, or ask for a picture first.",
+                    )
+                    .await?;
+                return Ok(true);
+            }
+        },
+    };
+    if tool == ai::image_tools::Tool::Describe {
+        let description = ai::vision::describe_image(&image)
+            .await
+            .unwrap_or("could not look at the picture".to_string());
+        transport.send_text(&description).await?;
+        return Ok(true);
+    }
+    let status_id = transport.send_status(tool.status()).await?;
+    let path = format!("./out/tool_{}.png", Utc::now().timestamp_millis());
+    let generation = async {
+        match tool {
+            ai::image_tools::Tool::Upscale => {
+                ai::image_tools::upscale(&image, argument.parse().ok(), &path).await?
+            }
+            ai::image_tools::Tool::RestoreFaces => {
+                ai::image_tools::restore_faces(&image, &path).await?
+            }
+            _ => ai::image_tools::remove_background(&image, &path).await?,
+        }
+        Ok(vec![Picture {
+            path: path.clone(),
+            buttons: vec![],
+            spoiler: false,
+        }])
+    };
+    let res = progress::generate_with_progress(
+        transport,
+        status_id.as_deref(),
+        tool.status(),
+        &path,
+        generation,
+    )
+    .await;
+    let _ = std::fs::remove_file(&path);
+    if let Err(e) = res {
+        log::error!("{:?}", e);
+        transport.send_text("could not change the picture").await?;
+    }
+    Ok(true)
+}
+
+/// queues the job, telling the user when it fails
+async fn generate_picture(
+    transport: &dyn Transport,
+    status_id: Option<String>,
+    job: queue::Job,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match queue::generate(transport, status_id, job).await {
+        Ok(_) => log::info!("picture generated"),
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => log::info!("picture cancelled"),
+        Err(e) => {
+            log::error!("{:?}", e);
+            transport
+                .send_text("could not generate the picture")
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// what /lastprompt shows
+fn remember_prompt(
+    transport: &dyn Transport,
+    prompt: &str,
+    settings: &ai::image_settings::ImageSettings,
+) {
+    if let Err(e) = history::file::write_last_prompt(
+        &ai::prompt::describe(prompt, settings),
+        transport.conversation().as_deref(),
+    ) {
+        log::error!("could not store the prompt {:?}", e);
+    }
+}
+
+/// transcribes the downloaded voice message and replies to it
+pub async fn voice_reply(transport: &dyn Transport, audio: Vec<u8>, history: History) {
+    let message = trace::Message::new("", true, &history, transport.conversation());
+    trace::record(message, transcribe_and_reply(transport, audio, history)).await
+}
+
+async fn transcribe_and_reply(transport: &dyn Transport, audio: Vec<u8>, history: History) {
+    let res = extract_text_from_audio(audio).await;
+    match res {
+        Ok(o) => {
+            trace::set_text(&o);
+            let heard_reply = transport.send_text(&format!("heard: {}", &o)).await;
+            match heard_reply {
+                Ok(o) => {
+                    log::trace!("heard reply: {:?}", o)
+                }
+                Err(e) => {
+                    log::error!("error: {}", e)
+                }
+            }
+            let res = ai_reply(transport, &o, history).await;
+            match res {
+                Ok(_) => {
+                    log::info!("ai has replied")
+                }
+                Err(e) => {
+                    log::error!("Error: {}", e)
+                }
+            }
+        }
+        Err(e) => {
+            log::error!("Error extracting audio {:?}", e);
+        }
+    }
+}
+
+/// the label of the strongest emotion huggingface finds in the text, e.g. Joy
+async fn strongest_mood(text: String) -> Option<String> {
+    let mut hg_config = huggingface_inference_rs::Config::default();
+    hg_config.key = get_ini_value("huggingface", "token").unwrap();
+    let hg_client = huggingface_inference_rs::Client::new(hg_config);
+    let mood = hg_client.get_emotions(text).await.ok()?;
+    let highest_scoring_mood = mood
+        .iter()
+        .max_by(|a, b| a.score.partial_cmp(&b.score).unwrap())?;
+    log::info!("mood: {:?}", highest_scoring_mood);
+    Some(format!("{:?}", highest_scoring_mood.label))
+}
+
+/// sends the last message of the history to the user, with mood sticker and voice if enabled
+pub async fn send_reply(transport: &dyn Transport, response: History) {
+    let response = ai::postprocess::clean_history(response);
+    match response.clone().last() {
+        Some(last_message) => {
+            match write_history_to_file(&response.clone(), transport.conversation().as_deref()) {
+                Ok(_) => {
+                    log::info!("history written to file")
+                }
+                Err(e) => {
+                    log::error!("error writing history to file{:?}", e)
+                }
+            }
+            // let out = send_string_to_server(last_message.clone()).await;
+            // log::info!("{:?}", out);
+            let res = transport.send_text(&last_message).await;
+            //if mood is enabled
+            if get_ini_value("huggingface", "mood").unwrap() == "true" {
+                let mood: Option<String> = trace::exchange(
+                    "mood",
+                    last_message.clone().into(),
+                    strongest_mood(last_message.to_owned()),
+                )
+                .await;
+                match mood {
+                    Some(mood) => {
+                        let res = transport
+                            .send_sticker(&format!("./stickers/{}.png", mood))
+                            .await;
+                        if let Err(e) = res {
+                            log::error!("{:?}", e);
+                        }
+                    }
+                    None => log::error!("could not get mood"),
+                }
+            }
+
+            match res {
+                Ok(_) => {
+                    // lets check if tts is enabled
+                    if get_ini_value("tts", "enabled").unwrap() == "true" {
+                        log::info!("message sent");
+                        let res = generate_voice(last_message.to_owned()).await;
+                        match res {
+                            Ok(path) => {
+                                let res = transport.send_voice(&path).await;
+                                match res {
+                                    Ok(_) => {}
+                                    Err(e) => {
+                                        log::error!("{:?}", e);
+                                    }
+                                }
+                                if let Err(e) = std::fs::remove_file(&path) {
+                                    log::error!("{:?}", e);
+                                }
+                            }
+                            Err(e) => {
+                                log::error!("{:?}", e);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("{:?}", e);
+                }
+            }
+        }
+        None => {
+            log::error!("some kind of error occured")
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::{future::Future, time::Duration};
+
+use crate::{
+    ai::{
+        self,
+        image_settings::setting,
+        safety::{self, Policy},
+    },
+    history,
+    pipeline::queue,
+    transport::{Picture, Transport},
+};
+
+/// Edits the status sent with send_status with the progress of the image while it is
+/// generated, with a preview every few steps when enabled, saved next to image_path.
+/// The pictures the generation returns replace the preview, a batch as an album.
+/// The safety policy of the chat can blur or block them, it also turns the previews off.
+pub async fn generate_with_progress<F>(
+    transport: &dyn Transport,
+    status_id: Option<&str>,
+    status: &str,
+    image_path: &str,
+    generation: F,
+) -> Result<(), std::io::Error>
+where
+    F: Future<Output = Result<Vec<Picture>, std::io::Error>>,
+{
+    let chat = queue::owner(transport);
+    let mut preview_id = None;
+    let preview_path = format!("{}_preview.png", image_path.trim_end_matches(".png"));
+    let result = if setting("progress", true) {
+        let interval = Duration::from_secs_f32(setting("progress_interval", 2.0f32).max(0.05));
+        let previews = setting("previews", false) && !safety::screened(&chat);
+        let preview_every = setting("preview_every", 5u32).max(1);
+        let mut last_preview_step = 0;
+        tokio::pin!(generation);
+        loop {
+            tokio::select! {
+                result = &mut generation => break result,
+                _ = tokio::time::sleep(interval) => {
+                    let progress = match ai::image::progress(image_path, previews).await {
+                        Some(progress) => progress,
+                        None => continue,
+                    };
+                    if let Some(id) = status_id {
+                        let text = format!(
+                            "{} {:.0}% (step {}/{}, about {:.0}s left)",
+                            status,
+                            progress.progress * 100.0,
+                            progress.state.sampling_step,
+                            progress.state.sampling_steps,
+                            progress.eta_relative
+                        );
+                        if let Err(e) = transport.edit_status(id, &text).await {
+                            log::error!("could not update the status {:?}", e);
+                        }
+                    }
+                    let step = progress.state.sampling_step;
+                    if previews && step >= last_preview_step + preview_every {
+                        if let Some(image) = &progress.current_image {
+                            last_preview_step = step;
+                            match ai::image::save_preview(image, &preview_path) {
+                                Ok(()) => match transport
+                                    .send_preview(preview_id.as_deref(), &preview_path)
+                                    .await
+                                {
+                                    Ok(id) => preview_id = id.or(preview_id),
+                                    Err(e) => log::error!("could not send the preview {:?}", e),
+                                },
+                                Err(e) => log::error!("could not save the preview {:?}", e),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    } else {
+        generation.await
+    };
+    let _ = std::fs::remove_file(&preview_path);
+    let mut pictures = vec![];
+    for mut picture in result? {
+        let image = std::fs::read(&picture.path)?;
+        match safety::judge(&chat, &image, "generated").await {
+            Policy::Allow => {}
+            Policy::Blur => picture.spoiler = true,
+            Policy::Block => continue,
+        }
+        // the image tools work on it without a photo
+        let conversation = transport.conversation();
+        if let Err(e) = history::file::write_last_image(&image, conversation.as_deref()) {
+            log::error!("could not keep the picture {:?}", e);
+        }
+        pictures.push(picture);
+    }
+    if pictures.is_empty() {
+        if let Err(e) = transport
+            .send_text("The picture has been blocked in this chat.")
+            .await
+        {
+            log::error!("{:?}", e);
+        }
+        return Ok(());
+    }
+    let res = match pictures.as_slice() {
+        [picture] => transport.send_picture(picture, preview_id.as_deref()).await,
+        pictures => transport.send_album(pictures, preview_id.as_deref()).await,
+    };
+    match res {
+        Ok(_) => log::info!("image sent"),
+        Err(e) => log::error!("{:?}", e),
+    }
+    Ok(())
+}
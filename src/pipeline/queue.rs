@@ -0,0 +1,443 @@
+use std::{
+    io::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, OnceLock,
+    },
+    time::Duration,
+};
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{
+    ai::{
+        self,
+        image_settings::{setting, ImageSettings},
+    },
+    config::get_ini_value,
+    pipeline::{progress, records},
+    transport::{Picture, Transport},
+};
+
+/// A picture waiting for or being generated by stable diffusion. It keeps everything
+/// needed to generate and deliver it, so it can be resumed after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: u64,
+    /// where to send the picture after a restart, see Transport::address
+    pub address: Option<String>,
+    /// who may cancel it
+    pub owner: String,
+    pub status: String,
+    pub prompt: String,
+    pub settings: ImageSettings,
+    /// base64 photo to change with img2img
+    pub image: Option<String>,
+    /// base64 inpainting mask for the photo
+    pub mask: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// read from the jobs file, its frontend has not resumed it yet
+    Saved,
+    Queued,
+    Running,
+    Cancelled,
+}
+
+struct Entry {
+    job: Job,
+    state: State,
+}
+
+static JOBS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static SLOTS: OnceLock<Semaphore> = OnceLock::new();
+/// how many permits SLOTS has been given
+static SLOT_COUNT: Mutex<usize> = Mutex::new(0);
+
+/// what /cancel did
+#[derive(Debug, PartialEq)]
+pub enum Cancelled {
+    Queued,
+    Running,
+    Nothing,
+}
+
+fn jobs() -> MutexGuard<'static, Vec<Entry>> {
+    JOBS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn jobs_file() -> String {
+    get_ini_value("sd_ai", "jobs_file")
+        .filter(|file| !file.is_empty())
+        .unwrap_or("./image_jobs.json".to_string())
+}
+
+/// writes the jobs that still have to be generated, so a restart does not lose them
+fn persist(jobs: &[Entry]) {
+    let pending: Vec<&Job> = jobs
+        .iter()
+        .filter(|entry| entry.state != State::Cancelled)
+        .map(|entry| &entry.job)
+        .collect();
+    let res = serde_json::to_string(&pending)
+        .map_err(Error::other)
+        .and_then(|json| std::fs::write(jobs_file(), json));
+    if let Err(e) = res {
+        log::error!("could not save the image jobs {:?}", e);
+    }
+}
+
+fn state(id: u64) -> Option<State> {
+    jobs()
+        .iter()
+        .find(|entry| entry.job.id == id)
+        .map(|entry| entry.state)
+}
+
+fn set_state(id: u64, state: State) {
+    let mut jobs = jobs();
+    if let Some(entry) = jobs.iter_mut().find(|entry| entry.job.id == id) {
+        entry.state = state;
+    }
+}
+
+/// the jobs a restart interrupted
+fn saved_jobs() -> Vec<Job> {
+    match std::fs::read_to_string(jobs_file()) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            log::error!("could not read the image jobs {:?}", e);
+            vec![]
+        }),
+        Err(_) => vec![],
+    }
+}
+
+/// Makes the ids go on after the kept records and saved jobs of an earlier run, the
+/// buttons of its pictures would act on a new one otherwise. Called once at startup.
+pub fn restore_ids() {
+    let highest = saved_jobs()
+        .iter()
+        .map(|job| job.id)
+        .fold(records::highest_id(), u64::max);
+    NEXT_ID.fetch_max(highest + 1, Ordering::SeqCst);
+}
+
+fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// The jobs stable diffusion may work on at once, `[sd_ai] concurrent_jobs`.
+/// A changed config is followed without a restart.
+fn slots() -> &'static Semaphore {
+    let slots = SLOTS.get_or_init(|| Semaphore::new(0));
+    let wanted = setting("concurrent_jobs", 1usize).max(1);
+    let mut count = SLOT_COUNT.lock().unwrap_or_else(|e| e.into_inner());
+    if wanted > *count {
+        slots.add_permits(wanted - *count);
+        *count = wanted;
+    }
+    // permits held by running jobs are taken away by a later call
+    while *count > wanted {
+        match slots.try_acquire() {
+            Ok(permit) => {
+                permit.forget();
+                *count -= 1;
+            }
+            Err(_) => break,
+        }
+    }
+    slots
+}
+
+/// 1 when the job is next
+fn position(id: u64) -> usize {
+    let jobs = jobs();
+    1 + jobs
+        .iter()
+        .take_while(|entry| entry.job.id != id)
+        .filter(|entry| entry.state == State::Queued)
+        .count()
+}
+
+fn cancelled() -> Error {
+    Error::new(
+        std::io::ErrorKind::Interrupted,
+        "the picture has been cancelled",
+    )
+}
+
+/// who may cancel the jobs of a transport, also the chat the safety policy is looked up for
+pub fn owner(transport: &dyn Transport) -> String {
+    transport
+        .address()
+        .or(transport.conversation())
+        .unwrap_or("default".to_string())
+}
+
+/// adds the negative prompt the safety policy requires for the role of the chat
+fn with_mandatory_negative(
+    transport: &dyn Transport,
+    mut settings: ImageSettings,
+) -> ImageSettings {
+    let negative = ai::safety::negative_prompt(&ai::safety::role(&owner(transport)));
+    if !negative.is_empty() && !settings.negative_prompt.contains(&negative) {
+        if !settings.negative_prompt.is_empty() {
+            settings.negative_prompt += ", ";
+        }
+        settings.negative_prompt += &negative;
+    }
+    settings
+}
+
+impl Job {
+    /// a txt2img job, or an img2img one when there is a photo
+    pub fn new(
+        transport: &dyn Transport,
+        status: &str,
+        prompt: String,
+        settings: ImageSettings,
+        image: Option<&[u8]>,
+        mask: Option<&[u8]>,
+    ) -> Self {
+        Job {
+            id: next_id(),
+            address: transport.address(),
+            owner: owner(transport),
+            status: status.to_string(),
+            prompt,
+            settings: with_mandatory_negative(transport, settings),
+            image: image.map(|image| general_purpose::STANDARD.encode(image)),
+            mask: mask.map(|mask| general_purpose::STANDARD.encode(mask)),
+        }
+    }
+
+    /// the job again with other settings, e.g. another seed for a reroll
+    pub fn again(&self, transport: &dyn Transport, status: &str, settings: ImageSettings) -> Self {
+        Job {
+            id: next_id(),
+            address: transport.address(),
+            owner: owner(transport),
+            status: status.to_string(),
+            settings: with_mandatory_negative(transport, settings),
+            ..self.clone()
+        }
+    }
+
+    async fn run(&self, path: &str) -> Result<Vec<ai::image::ImageInfo>, Error> {
+        let decode = |data: &str| general_purpose::STANDARD.decode(data).map_err(Error::other);
+        match &self.image {
+            Some(image) => {
+                let image = decode(image)?;
+                let mask = match &self.mask {
+                    Some(mask) => Some(decode(mask)?),
+                    None => None,
+                };
+                ai::image::edit_image(
+                    self.prompt.clone(),
+                    &self.settings,
+                    &image,
+                    mask.as_deref(),
+                    path,
+                )
+                .await
+            }
+            None => ai::image::generate_image_to(self.prompt.clone(), &self.settings, path).await,
+        }
+    }
+}
+
+/// Queues the job and waits for a free slot on the stable diffusion server, showing
+/// the position in the status meanwhile. Then generates and sends the picture.
+pub async fn generate(
+    transport: &dyn Transport,
+    status_id: Option<String>,
+    job: Job,
+) -> Result<(), Error> {
+    {
+        let mut jobs = jobs();
+        jobs.push(Entry {
+            job: job.clone(),
+            state: State::Queued,
+        });
+        persist(&jobs);
+    }
+    let result = wait_and_run(transport, status_id.as_deref(), &job).await;
+    let mut jobs = jobs();
+    jobs.retain(|entry| entry.job.id != job.id);
+    persist(&jobs);
+    result
+}
+
+async fn wait_and_run(
+    transport: &dyn Transport,
+    status_id: Option<&str>,
+    job: &Job,
+) -> Result<(), Error> {
+    let slots = slots();
+    let interval = Duration::from_secs_f32(setting("progress_interval", 2.0f32).max(0.05));
+    // the same acquire all along, so the job keeps its place
+    let acquire = slots.acquire();
+    tokio::pin!(acquire);
+    let mut wait = Duration::ZERO;
+    let mut shown = None;
+    let _permit = loop {
+        tokio::select! {
+            biased;
+            permit = &mut acquire => break permit.map_err(Error::other)?,
+            _ = tokio::time::sleep(wait) => {}
+        }
+        wait = interval;
+        if state(job.id) == Some(State::Cancelled) {
+            return Err(cancelled());
+        }
+        let position = position(job.id);
+        if shown != Some(position) {
+            shown = Some(position);
+            let text = format!("{} (number {} in the queue)", job.status, position);
+            let res = match status_id {
+                Some(id) => transport.edit_status(id, &text).await,
+                None => Ok(()),
+            };
+            if let Err(e) = res {
+                log::error!("could not show the queue position {:?}", e);
+            }
+        }
+    };
+    if state(job.id) == Some(State::Cancelled) {
+        return Err(cancelled());
+    }
+    set_state(job.id, State::Running);
+
+    let path = format!("./out/image_{}.png", job.id);
+    let generation = async {
+        let result = job.run(&path).await;
+        // an interrupted generation still returns what it had
+        if state(job.id) == Some(State::Cancelled) {
+            return Err(cancelled());
+        }
+        // every picture of a batch can be made again on its own
+        let mut pictures = vec![];
+        for (index, info) in result?.iter().enumerate() {
+            let mut picture = job.clone();
+            if index > 0 {
+                picture.id = next_id();
+            }
+            picture.settings.batch_size = 1;
+            if let Err(e) = records::store(&picture, info) {
+                log::error!("could not keep the image record {:?}", e);
+            }
+            pictures.push(Picture {
+                path: ai::image::batch_path(&path, index),
+                buttons: records::buttons(picture.id),
+                spoiler: false,
+            });
+        }
+        Ok(pictures)
+    };
+    let result =
+        progress::generate_with_progress(transport, status_id, &job.status, &path, generation)
+            .await;
+    for index in 0..job.settings.batch_size as usize {
+        let _ = std::fs::remove_file(ai::image::batch_path(&path, index));
+    }
+    result
+}
+
+/// Cancels the newest picture of the transport. A running one is interrupted on the server
+/// when it is the only one running there, the interrupt would stop the others too,
+/// otherwise its result is dropped.
+pub async fn cancel(transport: &dyn Transport) -> Cancelled {
+    let owner = owner(transport);
+    let (previous, running) = {
+        let mut jobs = jobs();
+        let running = jobs
+            .iter()
+            .filter(|entry| entry.state == State::Running)
+            .count();
+        let entry = jobs.iter_mut().rev().find(|entry| {
+            entry.job.owner == owner && matches!(entry.state, State::Queued | State::Running)
+        });
+        let previous = entry.map(|entry| {
+            let previous = entry.state;
+            entry.state = State::Cancelled;
+            previous
+        });
+        persist(&jobs);
+        (previous, running)
+    };
+    match previous {
+        Some(State::Running) => {
+            if running == 1 {
+                ai::image::interrupt().await;
+            }
+            Cancelled::Running
+        }
+        Some(_) => Cancelled::Queued,
+        None => Cancelled::Nothing,
+    }
+}
+
+/// Generates the pictures a restart interrupted whose address belongs to the frontend,
+/// e.g. "telegram" for "telegram:1234". transport_for finds the chat of an address.
+pub async fn resume<F>(frontend: &str, transport_for: F)
+where
+    F: Fn(&str) -> Option<Box<dyn Transport>>,
+{
+    let saved = saved_jobs();
+    let prefix = format!("{}:", frontend);
+    let resumed: Vec<(Job, Box<dyn Transport>)> = {
+        let mut jobs = jobs();
+        for job in saved {
+            if !jobs.iter().any(|entry| entry.job.id == job.id) {
+                NEXT_ID.fetch_max(job.id + 1, Ordering::SeqCst);
+                jobs.push(Entry {
+                    job,
+                    state: State::Saved,
+                });
+            }
+        }
+        let mut resumed = vec![];
+        jobs.retain(|entry| {
+            let address = entry.job.address.as_deref().unwrap_or_default();
+            if entry.state != State::Saved || !address.starts_with(&prefix) {
+                return true;
+            }
+            match transport_for(address) {
+                Some(transport) => {
+                    resumed.push((entry.job.clone(), transport));
+                    false
+                }
+                None => true,
+            }
+        });
+        resumed
+    };
+    if !resumed.is_empty() {
+        log::info!("resuming {} pictures for {}", resumed.len(), frontend);
+    }
+    let mut running = JoinSet::new();
+    for (job, transport) in resumed {
+        running.spawn(async move {
+            let status_id = match transport.send_status(&job.status).await {
+                Ok(id) => id,
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    None
+                }
+            };
+            generate(transport.as_ref(), status_id, job).await
+        });
+    }
+    while let Some(res) = running.join_next().await {
+        match res {
+            Ok(Err(e)) => log::error!("resumed picture failed {:?}", e),
+            Err(e) => log::error!("{:?}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+}
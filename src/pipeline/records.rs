@@ -0,0 +1,92 @@
+use std::io::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ai::{self, image::ImageInfo, image_settings::setting},
+    config::get_ini_value,
+    pipeline::queue::Job,
+    transport::Button,
+};
+
+/// A picture that has been sent, with the job that made it and what stable diffusion
+/// reported, so its buttons can make it again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub job: Job,
+    pub info: ImageInfo,
+}
+
+fn records_file() -> String {
+    get_ini_value("sd_ai", "records_file")
+        .filter(|file| !file.is_empty())
+        .unwrap_or("./image_records.json".to_string())
+}
+
+fn read() -> Vec<Record> {
+    match std::fs::read_to_string(records_file()) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            log::error!("could not read the image records {:?}", e);
+            vec![]
+        }),
+        Err(_) => vec![],
+    }
+}
+
+/// Keeps the picture of a finished job, with the seed it got in place of a random one.
+/// Only the newest `[sd_ai] kept_images` are kept.
+pub fn store(job: &Job, info: &ImageInfo) -> Result<(), Error> {
+    let mut job = job.clone();
+    if info.seed != 0 {
+        job.settings.seed = info.seed;
+    }
+    if job.settings.subseed_strength > 0.0 && info.subseed != 0 {
+        job.settings.subseed = info.subseed;
+    }
+    let mut records = read();
+    records.retain(|record| record.job.id != job.id);
+    records.push(Record {
+        job,
+        info: info.clone(),
+    });
+    let kept = setting("kept_images", 50usize);
+    let records = &records[records.len().saturating_sub(kept)..];
+    std::fs::write(
+        records_file(),
+        serde_json::to_string(records).map_err(Error::other)?,
+    )
+}
+
+/// the newest job id that has a record, 0 when there are none
+pub fn highest_id() -> u64 {
+    read().iter().map(|record| record.job.id).max().unwrap_or(0)
+}
+
+pub fn find(id: u64) -> Option<Record> {
+    read().into_iter().find(|record| record.job.id == id)
+}
+
+/// the buttons under a picture, their action is e.g. "reroll:12"
+pub fn buttons(id: u64) -> Vec<Button> {
+    [
+        ("🔄", "reroll"),
+        ("🎲", "variation"),
+        ("⬆", "upscale"),
+        ("📝", "prompt"),
+    ]
+    .iter()
+    .map(|(label, action)| Button {
+        label: label.to_string(),
+        action: format!("{}:{}", action, id),
+    })
+    .collect()
+}
+
+/// the prompt and parameters of the picture, with the model when the server named it
+pub fn describe(record: &Record) -> String {
+    let mut text = ai::prompt::describe(&record.job.prompt, &record.job.settings);
+    if let Some(model) = &record.info.sd_model_name {
+        text += &format!("\nmodel: {}", model);
+    }
+    text
+}
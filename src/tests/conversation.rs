@@ -0,0 +1,1423 @@
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+use std::time::Duration;
+use teloxide::types::ChatId;
+
+use super::{empty_history, mock, set_config, setup, RecordingTransport, Sent};
+use crate::{
+    ai::image_settings::ImageSettings,
+    character,
+    history::file::read_json_from_file,
+    modules::weather::get_weather,
+    pipeline::{
+        ai_reply, edit_reply, handle_command, image_action, image_tool, photo_reply, queue,
+        voice_reply,
+    },
+    trace,
+    transport::{
+        api,
+        telegram::{self, TelegramTransport},
+        Transport,
+    },
+};
+
+fn chat_requests() -> Vec<Value> {
+    mock::calls_to("/api/v1/chat")
+        .iter()
+        .map(|call| serde_json::from_str(&call.body).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn text_message_gets_the_model_reply() {
+    let _env = setup();
+    mock::queue_reply("hi, how was your day?");
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "hello", empty_history())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        transport.sent(),
+        vec![Sent::Text("hi, how was your day?".to_string())]
+    );
+    assert_eq!(chat_requests()[0]["user_input"], "hello");
+    let history = read_json_from_file(None).unwrap();
+    assert_eq!(history.last(), Some("hi, how was your day?".to_string()));
+}
+
+#[tokio::test]
+async fn history_is_sent_with_the_next_message() {
+    let _env = setup();
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "hello", empty_history())
+        .await
+        .unwrap();
+    let history = read_json_from_file(None).unwrap();
+    ai_reply(&transport, "how are you", history).await.unwrap();
+
+    let requests = chat_requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1]["user_input"], "how are you");
+    assert_eq!(
+        requests[1]["history"]["internal"][0],
+        serde_json::json!(["hello", mock::DEFAULT_REPLY])
+    );
+}
+
+#[tokio::test]
+async fn the_time_is_in_the_prompt_but_not_in_the_history() {
+    let _env = setup();
+    set_config("time", "enabled", "true");
+    set_config("time", "timezone", "Europe/Amsterdam");
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "good morning", empty_history())
+        .await
+        .unwrap();
+    let history = read_json_from_file(None).unwrap();
+    ai_reply(&transport, "how are you", history).await.unwrap();
+
+    let requests = chat_requests();
+    for request in &requests {
+        assert!(request["user_input"]
+            .as_str()
+            .unwrap()
+            .contains("current date: "));
+    }
+    assert_eq!(
+        requests[1]["history"]["internal"][0],
+        json!(["good morning", mock::DEFAULT_REPLY])
+    );
+    let history = read_json_from_file(None).unwrap();
+    assert_eq!(history.visible[1][0], "how are you");
+}
+
+#[tokio::test]
+async fn conversations_have_their_own_history() {
+    let _env = setup();
+    let transport = RecordingTransport {
+        conversation: Some("channel_1".to_string()),
+        ..Default::default()
+    };
+
+    ai_reply(&transport, "hello", empty_history())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        read_json_from_file(Some("channel_1")).unwrap().last(),
+        Some(mock::DEFAULT_REPLY.to_string())
+    );
+    assert_eq!(read_json_from_file(None).unwrap().last(), None);
+}
+
+#[tokio::test]
+async fn picture_request_sends_the_generated_image() {
+    let _env = setup();
+    let transport = RecordingTransport::default();
+    let mut history = empty_history();
+    history
+        .internal
+        .push(vec!["hi".to_string(), "hello".to_string()]);
+    history
+        .visible
+        .push(vec!["hi".to_string(), "hello".to_string()]);
+
+    mock::queue_reply("*takes a selfie on the beach*");
+    mock::queue_reply("1girl, beach, smiling");
+
+    ai_reply(&transport, "can i get a picture of you", history)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        transport.sent(),
+        vec![
+            Sent::Text("Generating picture...".to_string()),
+            Sent::Image(mock::IMAGE.to_vec()),
+        ]
+    );
+    let txt2img = mock::calls_to("/sdapi/v1/txt2img");
+    assert_eq!(txt2img.len(), 1);
+    assert!(txt2img[0].body.contains("1girl, beach, smiling"));
+}
+
+#[tokio::test]
+async fn voice_note_is_transcribed_before_the_reply() {
+    let _env = setup();
+    mock::set_transcript("what are you doing");
+    mock::queue_reply("thinking about you");
+    let transport = RecordingTransport::default();
+
+    voice_reply(&transport, b"mock ogg".to_vec(), empty_history()).await;
+
+    assert_eq!(
+        transport.sent(),
+        vec![
+            Sent::Text("heard: what are you doing".to_string()),
+            Sent::Text("thinking about you".to_string()),
+        ]
+    );
+    let paths: Vec<String> = mock::calls().into_iter().map(|call| call.path).collect();
+    assert_eq!(paths, vec!["/asr", "/api/v1/chat"]);
+    assert!(mock::calls_to("/asr")[0].body.contains("mock ogg"));
+    assert_eq!(chat_requests()[0]["user_input"], "what are you doing");
+}
+
+#[tokio::test]
+async fn reply_is_spoken_when_tts_is_enabled() {
+    let _env = setup();
+    set_config("tts", "enabled", "true");
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "hello", empty_history())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        transport.sent(),
+        vec![
+            Sent::Text(mock::DEFAULT_REPLY.to_string()),
+            Sent::Voice(mock::VOICE.to_vec()),
+        ]
+    );
+    assert_eq!(mock::calls_to("/cognitiveservices/v1").len(), 1);
+    // the spoken reply had its own file, which is gone once it is sent
+    let voices = std::fs::read_dir("./out")
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("voice_"))
+        .count();
+    assert_eq!(voices, 0);
+}
+
+#[tokio::test]
+async fn commands_reset_and_send_stickers() {
+    let _env = setup();
+    let transport = RecordingTransport::default();
+    ai_reply(&transport, "hello", empty_history())
+        .await
+        .unwrap();
+
+    assert!(handle_command(&transport, "/reset").await.unwrap());
+    assert!(handle_command(&transport, "/sticker").await.unwrap());
+    assert!(!handle_command(&transport, "/unknown").await.unwrap());
+
+    let sent = transport.sent();
+    assert_eq!(sent[1], Sent::Text("History has been reset.".to_string()));
+    assert!(matches!(sent[2], Sent::Sticker(_)));
+    assert_eq!(read_json_from_file(None).unwrap().last(), None);
+}
+
+#[tokio::test]
+async fn weather_is_fetched_for_the_city() {
+    let _env = setup();
+    set_config("openweather", "country", "NL");
+
+    let weather = get_weather("Amsterdam".to_string()).await;
+
+    assert_eq!(
+        weather,
+        Some("current temprature in Amsterdam is 12.5°C, it is light rain now, ".to_string())
+    );
+    assert!(mock::calls_to("/data/2.5/weather")[0]
+        .body
+        .contains("q=Amsterdam%2CNL"));
+
+    set_config("openweather", "country", "");
+    get_weather("Amsterdam".to_string()).await;
+    assert!(mock::calls_to("/data/2.5/weather")[1]
+        .body
+        .contains("q=Amsterdam&"));
+}
+
+#[tokio::test]
+async fn appointments_of_today_are_read_from_the_calendar() {
+    let env = setup();
+    set_config("calendar", "enabled", "true");
+    set_config("calendar", "url", &format!("{}/caldav/dav/", env.url));
+    set_config("calendar", "username", "tester");
+    set_config("calendar", "password", "test");
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "any appointments today?", empty_history())
+        .await
+        .unwrap();
+
+    assert!(mock::calls_to("/caldav/calendars/tester/personal/")
+        .iter()
+        .any(|call| call.path.starts_with("REPORT")));
+    let user_input = chat_requests()[0]["user_input"].to_string();
+    assert!(user_input.contains(&format!("{} at 10:00 AM", mock::APPOINTMENT)));
+}
+
+#[tokio::test]
+async fn lorebook_entries_of_mentioned_keywords_fit_their_budget() {
+    let _env = setup();
+    set_config("lorebook", "enabled", "true");
+    set_config("lorebook", "file", "./out/lorebook.json");
+    // the budget of the lorebook file wins, with this one nothing would fit
+    set_config("lorebook", "token_budget", "1");
+    let lorebook = json!({
+        "token_budget": 30,
+        "entries": {
+            "0": {
+                "key": ["dragon"],
+                "content": "Ember is a red dragon who lives under the castle and hoards silver.",
+                "token_budget": 8,
+                "order": 2
+            },
+            "1": { "keys": ["castle"], "content": "The castle was abandoned a hundred years ago.", "order": 1 },
+            "2": { "keys": ["forest"], "content": "The forest is full of wolves." }
+        }
+    });
+    std::fs::write("./out/lorebook.json", lorebook.to_string()).unwrap();
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "a Dragon on the castle?", empty_history())
+        .await
+        .unwrap();
+
+    let requests = chat_requests();
+    let input = requests[0]["user_input"].as_str().unwrap();
+    assert!(input.ends_with(
+        "| Ember is a red dragon who lives\nThe castle was abandoned a hundred years ago."
+    ));
+    assert!(!input.contains("wolves"));
+}
+
+#[tokio::test]
+async fn character_book_of_the_card_is_the_lorebook_without_a_file() {
+    let _env = setup();
+    set_config("lorebook", "enabled", "true");
+    set_config("lorebook", "file", "./out/no_lorebook.json");
+    set_config("chat_ai", "card", "./out/card.json");
+    let card = json!({
+        "spec": "chara_card_v2",
+        "data": {
+            "name": "Aqua",
+            "character_book": { "entries": [{
+                "keys": ["axis"],
+                "content": "Aqua leads the Axis church.",
+                "insertion_order": 1,
+                "priority": 10
+            }] }
+        }
+    });
+    let card = character::parse_card(card.to_string().as_bytes()).unwrap();
+    character::save_character(&card).unwrap();
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "tell me about axis", empty_history())
+        .await
+        .unwrap();
+
+    let requests = chat_requests();
+    let input = requests[0]["user_input"].as_str().unwrap();
+    assert!(input.ends_with("| Aqua leads the Axis church."));
+}
+
+#[tokio::test]
+async fn the_model_calls_the_weather_tool_before_answering() {
+    let env = setup();
+    set_config("tools", "enabled", "true");
+    set_config("tools", "backend", "openai");
+    let transport = RecordingTransport::default();
+    // without a url the message fails instead of the bot
+    assert!(ai_reply(&transport, "weather?", empty_history())
+        .await
+        .is_err());
+
+    set_config("tools", "url", &format!("{}/v1", env.url));
+    mock::queue_reply(r#"{"tool": "weather", "arguments": {"city": "Amsterdam"}}"#);
+    mock::queue_reply("light rain in Amsterdam, take an umbrella");
+    ai_reply(&transport, "how is the weather?", empty_history())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        transport.sent(),
+        vec![Sent::Text(
+            "light rain in Amsterdam, take an umbrella".to_string()
+        )]
+    );
+    assert!(mock::calls_to("/data/2.5/weather")[0]
+        .body
+        .contains("q=Amsterdam"));
+    let requests = mock::calls_to("/v1/chat/completions");
+    let answer: Value = serde_json::from_str(&requests[1].body).unwrap();
+    let tool = &answer["messages"].as_array().unwrap().last().unwrap();
+    assert_eq!(tool["role"], "tool");
+    assert!(tool["content"].as_str().unwrap().contains("light rain"));
+    let history = read_json_from_file(None).unwrap();
+    assert_eq!(history.internal[0][0], "how is the weather?");
+}
+
+#[tokio::test]
+async fn json_tool_results_stay_out_of_the_history() {
+    let _env = setup();
+    set_config("tools", "enabled", "true");
+    set_config("tools", "backend", "oobabooga");
+    mock::queue_reply(r#"{"tool": "weather", "arguments": {"city": "Amsterdam"}}"#);
+    mock::queue_reply("light rain in Amsterdam");
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "how is the weather?", empty_history())
+        .await
+        .unwrap();
+
+    let requests = chat_requests();
+    assert!(requests[0]["user_input"]
+        .as_str()
+        .unwrap()
+        .contains("You can use these tools"));
+    assert!(requests[1]["user_input"]
+        .as_str()
+        .unwrap()
+        .contains("result of the weather tool"));
+    let history = read_json_from_file(None).unwrap();
+    assert_eq!(history.internal[0][0], "how is the weather?");
+    assert_eq!(history.last(), Some("light rain in Amsterdam".to_string()));
+}
+
+#[tokio::test]
+async fn telegram_transport_talks_to_the_bot_api() {
+    let env = setup();
+    set_config("telegram", "api_url", &env.url);
+    set_config("tts", "enabled", "true");
+    mock::queue_reply("hi from telegram");
+    let transport = TelegramTransport {
+        bot: telegram::bot("123:test".to_string()),
+        chat_id: ChatId(1),
+    };
+
+    ai_reply(&transport, "hello", empty_history())
+        .await
+        .unwrap();
+
+    let messages = mock::calls_to("/bot123:test/sendMessage");
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].body.contains("hi from telegram"));
+    let voices = mock::calls_to("/bot123:test/sendVoice");
+    assert_eq!(voices.len(), 1);
+    assert!(voices[0].body.contains("mock mp3"));
+}
+
+#[tokio::test]
+async fn telegram_pictures_keep_their_parameters() {
+    let env = setup();
+    set_config("telegram", "api_url", &env.url);
+    mock::send_png();
+    let transport = TelegramTransport {
+        bot: telegram::bot("123:test".to_string()),
+        chat_id: ChatId(1),
+    };
+
+    ai_reply(&transport, "send me a picture of a cat", empty_history())
+        .await
+        .unwrap();
+
+    assert!(mock::calls_to("/bot123:test/sendPhoto").is_empty());
+    let documents = mock::calls_to("/bot123:test/sendDocument");
+    assert_eq!(documents.len(), 1);
+    assert!(documents[0]
+        .body
+        .contains("tEXtparameters\0mock parameters 0"));
+}
+
+#[tokio::test]
+async fn telegram_local_mode_shares_files_with_the_bot_api_server() {
+    let env = setup();
+    set_config("telegram", "api_url", &env.url);
+    set_config("telegram", "local_mode", "true");
+    std::fs::write("./out/local_voice.ogg", b"local ogg").unwrap();
+    let path = std::fs::canonicalize("./out/local_voice.ogg").unwrap();
+    let bot = telegram::bot("123:test".to_string());
+
+    let data = telegram::download(&bot, path.to_str().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(data, b"local ogg");
+    assert!(mock::calls().is_empty());
+
+    let transport = TelegramTransport {
+        bot,
+        chat_id: ChatId(1),
+    };
+    transport.send_voice("./out/local_voice.ogg").await.unwrap();
+    let voices = mock::calls_to("/bot123:test/sendVoice");
+    assert!(voices[0].body.contains("file:///"));
+    assert!(!voices[0].body.contains("local ogg"));
+}
+
+/// the api on a free port, clients have to send the key "secret"
+fn serve_api() -> String {
+    let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+        .serve(api::app("secret".to_string()).into_make_service());
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    url
+}
+
+#[tokio::test]
+async fn api_refuses_clients_without_the_key() {
+    let _env = setup();
+    let url = serve_api();
+    let client = reqwest::Client::new();
+    let body = json!({ "message": "hello" });
+
+    let wrong = client
+        .post(format!("{}/api/chat", url))
+        .bearer_auth("guess")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(wrong.status(), 401);
+    let missing = client
+        .post(format!("{}/api/chat", url))
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(missing.status(), 401);
+    assert!(mock::calls().is_empty());
+}
+
+#[tokio::test]
+async fn api_talks_with_the_telegram_history() {
+    let _env = setup();
+    let url = serve_api();
+    let client = reqwest::Client::new();
+    mock::queue_reply("hi from the api");
+    mock::queue_reply("still here");
+
+    let reply: Value = client
+        .post(format!("{}/api/chat", url))
+        .bearer_auth("secret")
+        .json(&json!({ "message": "hello" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(reply["reply"], "hi from the api");
+    assert_eq!(
+        read_json_from_file(None).unwrap().last(),
+        Some("hi from the api".to_string())
+    );
+
+    let completion: Value = client
+        .post(format!("{}/v1/chat/completions", url))
+        .header("x-api-key", "secret")
+        .json(&json!({ "messages": [{ "role": "user", "content": "are you there?" }] }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(completion["choices"][0]["message"]["content"], "still here");
+    assert_eq!(chat_requests()[1]["user_input"], "are you there?");
+    assert_eq!(read_json_from_file(None).unwrap().internal.len(), 2);
+}
+
+#[tokio::test]
+async fn recorded_trace_replays_without_backends() {
+    let _env = setup();
+    set_config("trace", "enabled", "true");
+    set_config("trace", "dir", "./traces");
+    set_config("tts", "enabled", "true");
+    let _ = std::fs::remove_dir_all("./traces");
+    mock::queue_reply("recorded reply");
+    let transport = RecordingTransport::default();
+    ai_reply(&transport, "hello", empty_history())
+        .await
+        .unwrap();
+
+    let trace_file = std::fs::read_dir("./traces")
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let (message, exchanges) = trace::read_trace(trace_file.to_str().unwrap()).unwrap();
+    assert_eq!(message.text, "hello");
+    let kinds: Vec<&str> = exchanges.iter().map(|e| e.kind.as_str()).collect();
+    assert_eq!(kinds, vec!["time", "chat", "tts"]);
+
+    mock::reset();
+    let replayed = RecordingTransport {
+        conversation: Some("replay".to_string()),
+        ..Default::default()
+    };
+    trace::replay(
+        exchanges.clone(),
+        ai_reply(&replayed, &message.text, message.history.clone()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(
+        replayed.sent(),
+        vec![
+            Sent::Text("recorded reply".to_string()),
+            Sent::Voice(mock::VOICE.to_vec()),
+        ]
+    );
+    assert!(mock::calls().is_empty());
+
+    // a reply that needs an exchange the trace does not have fails instead of asking the backend
+    let without_chat = exchanges.into_iter().filter(|e| e.kind != "chat").collect();
+    let res = trace::replay(
+        without_chat,
+        ai_reply(&replayed, &message.text, message.history),
+    )
+    .await;
+    assert_eq!(
+        res.err().as_deref(),
+        Some("no recorded chat exchange left in the trace")
+    );
+    assert!(mock::calls().is_empty());
+}
+
+#[tokio::test]
+async fn inline_options_change_the_picture_settings() {
+    let _env = setup();
+    set_config("sd_ai", "steps", "20");
+    set_config("sd_ai", "positive_promt", "masterpiece");
+    let transport = RecordingTransport::default();
+    let mut history = empty_history();
+    history
+        .internal
+        .push(vec!["hi".to_string(), "hello".to_string()]);
+    history
+        .visible
+        .push(vec!["hi".to_string(), "hello".to_string()]);
+
+    mock::queue_reply("*a sunny beach*");
+    mock::queue_reply("beach, sunset");
+
+    ai_reply(
+        &transport,
+        "--ar 2:3 send me a picture of the beach --steps 300 --sampler \"DPM++ 2M\" please",
+        history,
+    )
+    .await
+    .unwrap();
+
+    let payload: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/txt2img")[0].body).unwrap();
+    assert_eq!(payload["width"], 512);
+    assert_eq!(payload["height"], 768);
+    // clamped to max_steps
+    assert_eq!(payload["steps"], 150);
+    assert_eq!(payload["sampler_name"], "DPM++ 2M");
+    let prompt = payload["prompt"].as_str().unwrap();
+    assert_eq!(prompt, "masterpiece, beach, sunset");
+    assert!(!prompt.contains("--"));
+    assert!(!chat_requests()[0]["user_input"]
+        .as_str()
+        .unwrap()
+        .contains("--ar"));
+    assert!(chat_requests()[0]["user_input"]
+        .as_str()
+        .unwrap()
+        .starts_with("send me a picture of the beach please"));
+
+    ai_reply(&transport, "a picture --steps many", empty_history())
+        .await
+        .unwrap();
+    ai_reply(&transport, "a picture --colour red", empty_history())
+        .await
+        .unwrap();
+    let sent = transport.sent();
+    assert_eq!(
+        sent[sent.len() - 2..],
+        [
+            Sent::Text("--steps cannot be many.".to_string()),
+            Sent::Text("There is no picture option --colour.".to_string()),
+        ]
+    );
+    assert_eq!(mock::calls_to("/sdapi/v1/txt2img").len(), 1);
+
+    // clamped, then rounded down to a multiple of 8
+    mock::queue_reply("*a sunny beach*");
+    mock::queue_reply("beach, sunset");
+    ai_reply(&transport, "a picture --w 1001 --h 30", empty_history())
+        .await
+        .unwrap();
+    let payload: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/txt2img")[1].body).unwrap();
+    assert_eq!(payload["width"], 1000);
+    assert_eq!(payload["height"], 64);
+}
+
+#[tokio::test]
+async fn picture_prompt_combines_tags_appearance_and_lora() {
+    let _env = setup();
+    set_config("sd_ai", "positive_promt", "masterpiece");
+    set_config("sd_ai", "negative_promt", "lowres");
+    set_config("sd_ai", "appearance", "blue hair, green eyes");
+    set_config("sd_ai", "lora", "<lora:waifu:1>");
+    mock::queue_reply("*smiles and takes a selfie at the park*");
+    mock::queue_reply("Tags: 1girl, park, smiling.");
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "send me a photo of yourself", empty_history())
+        .await
+        .unwrap();
+
+    let payload: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/txt2img")[0].body).unwrap();
+    let prompt = "masterpiece, blue hair, green eyes, 1girl, park, smiling, <lora:waifu:1>";
+    assert_eq!(payload["prompt"], prompt);
+    assert_eq!(payload["negative_prompt"], "lowres");
+    // the question for the tags is not part of the conversation
+    let history = read_json_from_file(None).unwrap();
+    assert_eq!(history.internal.len(), 1);
+    assert_eq!(
+        history.last(),
+        Some("*smiles and takes a selfie at the park*".to_string())
+    );
+
+    assert!(handle_command(&transport, "/lastprompt").await.unwrap());
+    let Some(Sent::Text(shown)) = transport.sent().last().cloned() else {
+        panic!("no prompt shown");
+    };
+    assert!(shown.starts_with(&format!("prompt: {}\nnegative prompt: lowres\n", prompt)));
+}
+
+#[tokio::test]
+async fn selfies_follow_the_scene_and_the_appearance_sheet() {
+    let _env = setup();
+    set_config("scene", "enabled", "true");
+    let sheet = "./appearance_sheet_test.json";
+    std::fs::write(
+        sheet,
+        r#"{"Test": {"appearance": "1girl, red hair", "outfit": "school uniform", "lora": "<lora:test:0.8>"}}"#,
+    )
+    .unwrap();
+    set_config("sd_ai", "appearance_sheet", sheet);
+    mock::queue_reply("*sends a selfie from the beach*");
+    mock::queue_reply("1girl, selfie");
+    mock::queue_reply(
+        "```json\n{\"location\": \"beach\", \"outfit\": \"sundress\", \"time_of_day\": \"sunset\", \"activity\": \"\"}\n```",
+    );
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "send me a photo of yourself", empty_history())
+        .await
+        .unwrap();
+
+    let payload: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/txt2img")[0].body).unwrap();
+    assert_eq!(
+        payload["prompt"],
+        "1girl, red hair, sundress, selfie, beach, sunset, <lora:test:0.8>"
+    );
+
+    assert!(handle_command(&transport, "/scene outfit bikini")
+        .await
+        .unwrap());
+    assert_eq!(
+        transport.sent().last(),
+        Some(&Sent::Text(
+            "location: beach\noutfit: bikini\ntime: sunset\nactivity: -".to_string()
+        ))
+    );
+    assert!(handle_command(&transport, "/scene clear").await.unwrap());
+    mock::queue_reply("*waves*");
+    mock::queue_reply("1girl, waving");
+    ai_reply(&transport, "send me a photo of yourself", empty_history())
+        .await
+        .unwrap();
+    let payload: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/txt2img")[1].body).unwrap();
+    assert_eq!(
+        payload["prompt"],
+        "1girl, red hair, school uniform, waving, <lora:test:0.8>"
+    );
+    std::fs::remove_file(sheet).unwrap();
+}
+
+#[tokio::test]
+async fn nsfw_pictures_are_blurred_or_blocked_by_the_chat_policy() {
+    let env = setup();
+    set_config("safety", "enabled", "true");
+    set_config("safety", "url", &format!("{}/classify", env.url));
+    set_config("safety", "policy", "blur");
+    set_config("safety", "roles", "guests=guest");
+    set_config("safety", "guest_policy", "block");
+    set_config("safety", "guest_negative_prompt", "nsfw, nude");
+    mock::queue_reply("*takes a selfie*");
+    mock::queue_reply("1girl, selfie");
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "send me a photo of yourself", empty_history())
+        .await
+        .unwrap();
+    assert_eq!(
+        transport.sent().last(),
+        Some(&Sent::Spoiler(mock::IMAGE.to_vec()))
+    );
+
+    let guests = in_conversation("guests");
+    handle_command(&guests, "/imagine a cat").await.unwrap();
+    let request: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/txt2img")[1].body).unwrap();
+    assert_eq!(request["negative_prompt"], "nsfw, nude");
+    assert_eq!(
+        guests.sent().last(),
+        Some(&Sent::Text(
+            "The picture has been blocked in this chat.".to_string()
+        ))
+    );
+    photo_reply(&guests, "look", mock::IMAGE, empty_history())
+        .await
+        .unwrap();
+    assert_eq!(
+        guests.sent().last(),
+        Some(&Sent::Text(
+            "That photo is not allowed in this chat.".to_string()
+        ))
+    );
+    assert!(mock::calls_to("/sdapi/v1/interrogate").is_empty());
+    assert_eq!(mock::calls_to("/classify").len(), 3);
+}
+
+#[tokio::test]
+async fn pictures_of_the_image_tool_go_through_the_queue_and_the_safety_policy() {
+    let env = setup();
+    set_config("tools", "enabled", "true");
+    set_config("tools", "backend", "oobabooga");
+    set_config("safety", "enabled", "true");
+    set_config("safety", "url", &format!("{}/classify", env.url));
+    set_config("safety", "roles", "guests=guest");
+    set_config("safety", "guest_policy", "block");
+    set_config("safety", "guest_negative_prompt", "nsfw, nude");
+    mock::queue_reply(r#"{"tool": "image", "arguments": {"prompt": "a cat on the beach"}}"#);
+    mock::queue_reply("here is your cat");
+    let guests = in_conversation("guests");
+
+    ai_reply(&guests, "draw me a cat", empty_history())
+        .await
+        .unwrap();
+
+    let request: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/txt2img")[0].body).unwrap();
+    assert!(request["prompt"]
+        .as_str()
+        .unwrap()
+        .contains("a cat on the beach"));
+    assert_eq!(request["negative_prompt"], "nsfw, nude");
+    assert_eq!(
+        guests.sent(),
+        vec![
+            Sent::Text("here is your cat".to_string()),
+            Sent::Text("Generating picture...".to_string()),
+            Sent::Text("The picture has been blocked in this chat.".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn image_tools_work_on_the_last_picture_or_a_sent_photo() {
+    let env = setup();
+    set_config(
+        "image_tools",
+        "rembg_url",
+        &format!("{}/api/remove", env.url),
+    );
+    let transport = RecordingTransport::default();
+
+    assert!(handle_command(&transport, "/upscale").await.unwrap());
+    assert_eq!(
+        transport.sent(),
+        vec![Sent::Text(
+            "Send a photo with the command as caption, or ask for a picture first.".to_string()
+        )]
+    );
+
+    handle_command(&transport, "/imagine a cat").await.unwrap();
+    assert!(handle_command(&transport, "/upscale 4").await.unwrap());
+    let request: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/extra-single-image")[0].body).unwrap();
+    assert_eq!(
+        request["image"],
+        general_purpose::STANDARD.encode(mock::IMAGE)
+    );
+    assert_eq!(request["upscaling_resize"], 4.0);
+    assert_eq!(request["upscaler_1"], "R-ESRGAN 4x+");
+    assert_eq!(
+        transport.sent().last(),
+        Some(&Sent::Image(mock::UPSCALED.to_vec()))
+    );
+
+    assert!(image_tool(&transport, "/remove_bg", Some(b"my photo"))
+        .await
+        .unwrap());
+    assert!(mock::calls_to("/api/remove")[0].body.contains("my photo"));
+    assert_eq!(
+        transport.sent().last(),
+        Some(&Sent::Image(mock::CUTOUT.to_vec()))
+    );
+
+    // the cutout is the last picture now
+    assert!(handle_command(&transport, "/describe").await.unwrap());
+    let request: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/interrogate")[0].body).unwrap();
+    assert_eq!(
+        request["image"],
+        general_purpose::STANDARD.encode(mock::CUTOUT)
+    );
+    assert_eq!(
+        transport.sent().last(),
+        Some(&Sent::Text(mock::CAPTION.to_string()))
+    );
+    assert!(!image_tool(&transport, "make it blue", Some(b"my photo"))
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn photo_is_edited_with_img2img_and_a_mask() {
+    let _env = setup();
+    mock::queue_reply("anime style, 1boy, kimono");
+    let transport = RecordingTransport::default();
+    // just the png header of a 300x200 photo
+    let mut photo = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+    photo.extend_from_slice(&300u32.to_be_bytes());
+    photo.extend_from_slice(&200u32.to_be_bytes());
+    let mask = b"mask".to_vec();
+
+    edit_reply(
+        &transport,
+        "put me in a kimono --strength 0.4",
+        &photo,
+        Some(&mask),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        transport.sent(),
+        vec![
+            Sent::Text("Editing picture...".to_string()),
+            Sent::Image(mock::IMAGE.to_vec()),
+        ]
+    );
+    assert!(chat_requests()[0]["user_input"]
+        .as_str()
+        .unwrap()
+        .contains("put me in a kimono"));
+    let payload: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/img2img")[0].body).unwrap();
+    assert_eq!(payload["prompt"], "anime style, 1boy, kimono");
+    assert_eq!(
+        payload["init_images"][0],
+        general_purpose::STANDARD.encode(&photo)
+    );
+    assert_eq!(payload["mask"], general_purpose::STANDARD.encode(&mask));
+    assert_eq!(payload["denoising_strength"], 0.4);
+    assert_eq!(payload["width"], 768);
+    assert_eq!(payload["height"], 512);
+}
+
+#[tokio::test]
+async fn photo_is_described_before_the_reply() {
+    let _env = setup();
+    let transport = RecordingTransport::default();
+
+    photo_reply(
+        &transport,
+        "look at this picture",
+        b"jpeg data",
+        empty_history(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        transport.sent(),
+        vec![Sent::Text(mock::DEFAULT_REPLY.to_string())]
+    );
+    let interrogate: Value =
+        serde_json::from_str(&mock::calls_to("/sdapi/v1/interrogate")[0].body).unwrap();
+    assert_eq!(
+        interrogate["image"],
+        general_purpose::STANDARD.encode("jpeg data")
+    );
+    // the caption mentions a picture, but she should not send one
+    assert!(mock::calls_to("/sdapi/v1/txt2img").is_empty());
+    assert_eq!(
+        chat_requests()[0]["user_input"],
+        format!(
+            "*sends you a photo: {}* look at this picture",
+            mock::CAPTION
+        )
+    );
+
+    let history = read_json_from_file(None).unwrap();
+    assert!(history.internal[0][0].contains(mock::CAPTION));
+    let visible = &history.visible[0][0];
+    assert!(visible.starts_with("[photo ./photos/"));
+    assert!(visible.ends_with(".jpg] look at this picture"));
+    let path = visible[7..visible.find(']').unwrap()].to_string();
+    assert_eq!(std::fs::read(path).unwrap(), b"jpeg data");
+}
+
+#[tokio::test]
+async fn photo_can_be_described_by_a_vision_model() {
+    let env = setup();
+    set_config("vision", "backend", "http");
+    set_config("vision", "url", &format!("{}/api/generate", env.url));
+    set_config("vision", "model", "llava");
+    let transport = RecordingTransport::default();
+
+    photo_reply(&transport, "", b"jpeg data", empty_history())
+        .await
+        .unwrap();
+
+    let request: Value = serde_json::from_str(&mock::calls_to("/api/generate")[0].body).unwrap();
+    assert_eq!(request["model"], "llava");
+    assert_eq!(
+        request["images"][0],
+        general_purpose::STANDARD.encode("jpeg data")
+    );
+    assert_eq!(
+        chat_requests()[0]["user_input"],
+        format!("*sends you a photo: {}*", mock::CAPTION)
+    );
+}
+
+#[tokio::test]
+async fn picture_progress_is_shown_while_generating() {
+    let _env = setup();
+    set_config("sd_ai", "progress_interval", "0.05");
+    set_config("sd_ai", "previews", "true");
+    set_config("sd_ai", "preview_every", "1");
+    mock::set_image_delay(std::time::Duration::from_millis(400));
+    let transport = RecordingTransport::default();
+
+    ai_reply(&transport, "send me a picture of a cat", empty_history())
+        .await
+        .unwrap();
+
+    let sent = transport.sent();
+    assert_eq!(sent[0], Sent::Text("Generating picture...".to_string()));
+    assert!(sent.contains(&Sent::Edit(
+        "Generating picture... 50% (step 10/20, about 3s left)".to_string()
+    )));
+    let previews: Vec<&Sent> = sent
+        .iter()
+        .filter(|sent| matches!(sent, Sent::Preview(_)))
+        .collect();
+    // one preview at step 10, then the final image in its place
+    assert_eq!(
+        previews,
+        vec![
+            &Sent::Preview(mock::PREVIEW.to_vec()),
+            &Sent::Preview(mock::IMAGE.to_vec())
+        ]
+    );
+    assert!(!sent.iter().any(|sent| matches!(sent, Sent::Image(_))));
+}
+
+fn in_conversation(name: &str) -> RecordingTransport {
+    RecordingTransport {
+        conversation: Some(name.to_string()),
+        ..Default::default()
+    }
+}
+
+fn images(transport: &RecordingTransport) -> usize {
+    transport
+        .sent()
+        .iter()
+        .filter(|sent| matches!(sent, Sent::Image(_)))
+        .count()
+}
+
+/// waits until the condition holds, the other futures of the test make progress meanwhile
+async fn wait_until(condition: impl Fn() -> bool) {
+    for _ in 0..200 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out");
+}
+
+#[tokio::test]
+async fn pictures_wait_in_the_queue_and_can_be_cancelled() {
+    let _env = setup();
+    set_config("sd_ai", "progress", "false");
+    set_config("sd_ai", "progress_interval", "0.05");
+    mock::hold_images();
+    let (first, second, third) = (
+        in_conversation("first"),
+        in_conversation("second"),
+        in_conversation("third"),
+    );
+    let queued = |transport: &RecordingTransport, position: usize| {
+        transport.sent().contains(&Sent::Edit(format!(
+            "Generating picture... (number {} in the queue)",
+            position
+        )))
+    };
+
+    let (a, b, c, _) = tokio::join!(
+        ai_reply(&first, "send me a picture of a cat", empty_history()),
+        async {
+            wait_until(|| mock::calls_to("/sdapi/v1/txt2img").len() == 1).await;
+            ai_reply(&second, "send me a picture of a dog", empty_history()).await
+        },
+        async {
+            wait_until(|| queued(&second, 1)).await;
+            ai_reply(&third, "send me a picture of a bird", empty_history()).await
+        },
+        async {
+            wait_until(|| queued(&third, 2)).await;
+            handle_command(&third, "/cancel").await.unwrap();
+            mock::release_images();
+        },
+    );
+    a.unwrap();
+    b.unwrap();
+    c.unwrap();
+
+    assert_eq!(images(&first), 1);
+    assert_eq!(images(&second), 1);
+    assert!(third.sent().contains(&Sent::Text(
+        "The picture has been taken out of the queue.".to_string()
+    )));
+    assert_eq!(images(&third), 0);
+    assert_eq!(mock::calls_to("/sdapi/v1/txt2img").len(), 2);
+}
+
+#[tokio::test]
+async fn running_picture_is_interrupted() {
+    let _env = setup();
+    mock::set_image_delay(Duration::from_millis(300));
+    let transport = RecordingTransport::default();
+
+    let (res, _) = tokio::join!(
+        ai_reply(&transport, "send me a picture of a cat", empty_history()),
+        async {
+            wait_until(|| mock::calls_to("/sdapi/v1/txt2img").len() == 1).await;
+            handle_command(&transport, "/cancel").await.unwrap();
+        },
+    );
+    res.unwrap();
+
+    assert_eq!(mock::calls_to("/sdapi/v1/interrupt").len(), 1);
+    assert!(transport
+        .sent()
+        .contains(&Sent::Text("The picture has been stopped.".to_string())));
+    assert_eq!(images(&transport), 0);
+    assert!(handle_command(&transport, "/cancel").await.unwrap());
+    assert_eq!(
+        transport.sent().last(),
+        Some(&Sent::Text("There is no picture to cancel.".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn cancelling_one_of_two_running_pictures_does_not_stop_the_other() {
+    let _env = setup();
+    set_config("sd_ai", "concurrent_jobs", "2");
+    mock::hold_images();
+    let (first, second) = (in_conversation("first"), in_conversation("second"));
+
+    let (a, b, _) = tokio::join!(
+        ai_reply(&first, "send me a picture of a cat", empty_history()),
+        ai_reply(&second, "send me a picture of a dog", empty_history()),
+        async {
+            wait_until(|| mock::calls_to("/sdapi/v1/txt2img").len() == 2).await;
+            handle_command(&second, "/cancel").await.unwrap();
+            mock::release_images();
+        },
+    );
+    a.unwrap();
+    b.unwrap();
+
+    assert!(mock::calls_to("/sdapi/v1/interrupt").is_empty());
+    assert!(second
+        .sent()
+        .contains(&Sent::Text("The picture has been stopped.".to_string())));
+    assert_eq!(images(&second), 0);
+    assert_eq!(images(&first), 1);
+}
+
+#[tokio::test]
+async fn queued_pictures_are_resumed_after_a_restart() {
+    let _env = setup();
+    let mut job = queue::Job::new(
+        &RecordingTransport::default(),
+        "Generating picture...",
+        "a resumed cat".to_string(),
+        ImageSettings::from_config(),
+        None,
+        None,
+    );
+    job.address = Some("test:1".to_string());
+    std::fs::write(
+        "./image_jobs.json",
+        serde_json::to_string(&vec![job]).unwrap(),
+    )
+    .unwrap();
+
+    queue::resume("other", |_| None).await;
+    assert!(mock::calls_to("/sdapi/v1/txt2img").is_empty());
+    queue::resume("test", |address| {
+        assert_eq!(address, "test:1");
+        Some(Box::new(RecordingTransport::default()))
+    })
+    .await;
+
+    let txt2img = mock::calls_to("/sdapi/v1/txt2img");
+    assert_eq!(txt2img.len(), 1);
+    assert!(txt2img[0].body.contains("a resumed cat"));
+    assert_eq!(std::fs::read_to_string("./image_jobs.json").unwrap(), "[]");
+}
+
+#[tokio::test]
+async fn pictures_can_be_rerolled_and_varied_with_their_buttons() {
+    let _env = setup();
+    let transport = RecordingTransport::default();
+    mock::queue_reply("*takes a selfie in the park*");
+    mock::queue_reply("1girl, park");
+
+    ai_reply(&transport, "can i get a picture of you", empty_history())
+        .await
+        .unwrap();
+
+    let buttons = transport.buttons()[0].clone();
+    let actions: Vec<&str> = buttons
+        .iter()
+        .map(|button| button.action.split(':').next().unwrap())
+        .collect();
+    assert_eq!(actions, ["reroll", "variation", "upscale", "prompt"]);
+    let id = buttons[0].action.split_once(':').unwrap().1;
+
+    image_action(&transport, &format!("prompt:{}", id))
+        .await
+        .unwrap();
+    match transport.sent().last() {
+        Some(Sent::Text(text)) => {
+            assert!(text.contains("1girl, park"));
+            assert!(text.contains(&format!("seed {}", mock::SEED)));
+            assert!(text.contains("model: mock model"));
+        }
+        other => panic!("expected the prompt, got {:?}", other),
+    }
+
+    image_action(&transport, &format!("variation:{}", id))
+        .await
+        .unwrap();
+    image_action(&transport, &format!("reroll:{}", id))
+        .await
+        .unwrap();
+    let requests: Vec<Value> = mock::calls_to("/sdapi/v1/txt2img")
+        .iter()
+        .map(|call| serde_json::from_str(&call.body).unwrap())
+        .collect();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[1]["seed"], mock::SEED);
+    assert_eq!(requests[1]["subseed_strength"], 0.3);
+    assert_eq!(requests[2]["seed"], -1);
+    assert!(requests[2]["prompt"]
+        .as_str()
+        .unwrap()
+        .contains("1girl, park"));
+
+    image_action(&transport, "reroll:9999").await.unwrap();
+    assert_eq!(
+        transport.sent().last(),
+        Some(&Sent::Text("That picture is not kept anymore.".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn buttons_of_an_earlier_run_keep_their_picture() {
+    let _env = setup();
+    let transport = RecordingTransport::default();
+    mock::queue_reply("*takes a selfie in the park*");
+    mock::queue_reply("1girl, park");
+    ai_reply(&transport, "can i get a picture of you", empty_history())
+        .await
+        .unwrap();
+    // an earlier run got further, its newest picture has the id this run hands out next
+    let mut records: Vec<Value> =
+        serde_json::from_str(&std::fs::read_to_string("./image_records.json").unwrap()).unwrap();
+    let old = records[0]["job"]["id"].as_u64().unwrap() + 1;
+    records[0]["job"]["id"] = json!(old);
+    std::fs::write("./image_records.json", Value::from(records).to_string()).unwrap();
+    queue::restore_ids();
+
+    mock::queue_reply("*takes a selfie on the beach*");
+    mock::queue_reply("1girl, beach");
+    ai_reply(&transport, "can i get a picture of you", empty_history())
+        .await
+        .unwrap();
+    assert_ne!(transport.buttons()[1][0].action, format!("reroll:{}", old));
+    image_action(&transport, &format!("reroll:{}", old))
+        .await
+        .unwrap();
+
+    let txt2img = mock::calls_to("/sdapi/v1/txt2img");
+    assert_eq!(txt2img.len(), 3);
+    assert!(txt2img[1].body.contains("1girl, beach"));
+    assert!(txt2img[2].body.contains("1girl, park"));
+}
+
+#[tokio::test]
+async fn batches_have_buttons_for_every_picture() {
+    let _env = setup();
+    let transport = RecordingTransport::default();
+
+    assert!(handle_command(&transport, "/imagine n=3 a cat in the rain")
+        .await
+        .unwrap());
+
+    let txt2img = mock::calls_to("/sdapi/v1/txt2img");
+    let request: Value = serde_json::from_str(&txt2img[0].body).unwrap();
+    assert_eq!(request["batch_size"], 3);
+    assert!(request["prompt"]
+        .as_str()
+        .unwrap()
+        .contains("a cat in the rain"));
+    assert_eq!(images(&transport), 3);
+    let buttons = transport.buttons();
+    assert_eq!(buttons.len(), 3);
+    let third = buttons[2][3].action.clone();
+    assert!(third.starts_with("prompt:"));
+    assert_ne!(third, buttons[0][3].action);
+
+    image_action(&transport, &third).await.unwrap();
+    match transport.sent().last() {
+        Some(Sent::Text(text)) => assert!(text.contains(&format!("seed {}", mock::SEED + 2))),
+        other => panic!("expected the prompt, got {:?}", other),
+    }
+
+    mock::queue_reply("*takes two selfies*");
+    mock::queue_reply("1girl, mirror");
+    ai_reply(&transport, "show me 2 pictures of you", empty_history())
+        .await
+        .unwrap();
+    let txt2img = mock::calls_to("/sdapi/v1/txt2img");
+    let request: Value = serde_json::from_str(&txt2img[1].body).unwrap();
+    assert_eq!(request["batch_size"], 2);
+    assert_eq!(images(&transport), 5);
+}
+
+#[tokio::test]
+async fn comfyui_runs_the_workflow_template() {
+    let _env = setup();
+    set_config("sd_ai", "backend", "comfyui");
+    set_config("sd_ai", "lora", "<lora:mika:0.7>");
+    set_config("sd_ai", "workflow", "./config/comfyui_workflow.json");
+    std::fs::write(
+        "./config/comfyui_workflow.json",
+        include_str!("../../config/comfyui_workflow.json"),
+    )
+    .unwrap();
+    let transport = RecordingTransport::default();
+
+    handle_command(&transport, "/imagine a photo of you at the beach --seed 42")
+        .await
+        .unwrap();
+    handle_command(&transport, "/imagine a cat in the rain")
+        .await
+        .unwrap();
+
+    let workflows: Vec<Value> = mock::calls_to("/prompt")
+        .iter()
+        .map(|call| serde_json::from_str::<Value>(&call.body).unwrap()["prompt"].clone())
+        .collect();
+    assert_eq!(workflows.len(), 2);
+    assert_eq!(workflows[0]["3"]["inputs"]["seed"], 42);
+    assert_eq!(workflows[0]["5"]["inputs"]["width"], 512);
+    assert_eq!(
+        workflows[0]["6"]["inputs"]["text"],
+        "a photo of you at the beach"
+    );
+    assert_eq!(
+        workflows[0]["10"]["inputs"]["lora_name"],
+        "mika.safetensors"
+    );
+    assert_eq!(workflows[0]["10"]["inputs"]["strength_model"], 0.7);
+    // without a lora in the prompt its loader is left out
+    assert!(workflows[1].get("10").is_none());
+    assert_eq!(workflows[1]["3"]["inputs"]["model"], json!(["4", 0]));
+    assert_eq!(workflows[1]["6"]["inputs"]["clip"], json!(["4", 1]));
+    assert!(mock::calls_to("/view")[0]
+        .body
+        .contains("waifu_bot_00001_.png"));
+    assert_eq!(images(&transport), 2);
+
+    // a variation has no subseed in comfyui, it gets a new seed
+    let buttons = transport.buttons()[0].clone();
+    let id = buttons[1].action.split_once(':').unwrap().1;
+    image_action(&transport, &format!("variation:{}", id))
+        .await
+        .unwrap();
+    let workflow: Value = serde_json::from_str(&mock::calls_to("/prompt")[2].body).unwrap();
+    assert_ne!(workflow["prompt"]["3"]["inputs"]["seed"], 42);
+    assert_eq!(workflow["prompt"]["10"]["inputs"]["strength_model"], 0.7);
+}
+
+#[tokio::test]
+async fn comfyui_progress_is_followed_for_every_workflow() {
+    let _env = setup();
+    set_config("sd_ai", "backend", "comfyui");
+    set_config("sd_ai", "workflow", "./config/comfyui_workflow.json");
+    std::fs::write(
+        "./config/comfyui_workflow.json",
+        include_str!("../../config/comfyui_workflow.json"),
+    )
+    .unwrap();
+    set_config("sd_ai", "concurrent_jobs", "2");
+    set_config("sd_ai", "progress_interval", "0.05");
+    set_config("sd_ai", "previews", "true");
+    set_config("sd_ai", "preview_every", "1");
+    mock::hold_images();
+    let (first, second) = (in_conversation("first"), in_conversation("second"));
+    let step = |transport: &RecordingTransport, number: usize| {
+        let step = format!("(step {}/20,", mock::comfy_step(number));
+        transport
+            .sent()
+            .iter()
+            .any(|sent| matches!(sent, Sent::Edit(text) if text.contains(&step)))
+    };
+
+    let (a, b, _) = tokio::join!(
+        handle_command(&first, "/imagine a cat"),
+        async {
+            wait_until(|| mock::calls_to("/prompt").len() == 1).await;
+            handle_command(&second, "/imagine a dog").await
+        },
+        async {
+            wait_until(|| step(&first, 1) && step(&second, 2)).await;
+            mock::release_images();
+        },
+    );
+    a.unwrap();
+    b.unwrap();
+
+    assert_eq!(mock::calls_to("/ws").len(), 2);
+    assert!(!step(&first, 2));
+    assert!(!step(&second, 1));
+    for transport in [&first, &second] {
+        assert!(transport
+            .sent()
+            .contains(&Sent::Preview(mock::PREVIEW.to_vec())));
+    }
+}
@@ -0,0 +1,485 @@
+//! In-process stand-ins for the http backends (oobabooga, an openai compatible server,
+//! automatic1111, comfyui, whisper, azure tts, openweathermap, an ollama vision model,
+//! an nsfw classifier, rembg, a caldav server) and the telegram bot api.
+//! Everything they receive is recorded so tests can check it.
+
+use std::{collections::VecDeque, net::TcpListener, sync::Mutex};
+
+use async_tungstenite::{
+    tokio::TokioAdapter,
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, RawQuery},
+    http::{header, Method, Request, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose, Engine as _};
+use futures_util::SinkExt;
+use serde_json::{json, Value};
+
+/// a request one of the mock backends received
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub path: String,
+    pub body: String,
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<Call>,
+    /// what the language model answers next, a default answer when empty
+    replies: VecDeque<String>,
+    transcript: String,
+    /// how long stable diffusion takes for an image
+    image_delay: std::time::Duration,
+    /// stable diffusion does not answer until the images are released
+    held: bool,
+    /// stable diffusion sends a real png instead of IMAGE
+    png: bool,
+    /// the client ids of the comfyui workflows, the first is mock-prompt-1
+    comfy_clients: Vec<String>,
+}
+
+static STATE: Mutex<Option<MockState>> = Mutex::new(None);
+
+pub const DEFAULT_REPLY: &str = "hello from the mock";
+/// the bytes the mock text to speech sends back
+pub const VOICE: &[u8] = b"mock mp3";
+/// the bytes of the image the mock stable diffusion generates
+pub const IMAGE: &[u8] = b"mock png";
+/// a 1x1 grey png, for what has to read the png
+pub const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\
+    \0\0\0\x0dIHDR\0\0\0\x01\0\0\0\x01\x08\0\0\0\0\x3a\x7e\x9b\x55\
+    \0\0\0\x0aIDAT\x78\x9c\x63\x60\0\0\0\x02\0\x01\x48\xaf\xa4\x71\
+    \0\0\0\0IEND\xae\x42\x60\x82";
+/// what the extras tab and rembg make of a picture
+pub const UPSCALED: &[u8] = b"mock upscaled png";
+pub const CUTOUT: &[u8] = b"mock cutout png";
+/// the seed txt2img reports
+pub const SEED: i64 = 1234;
+
+fn with_state<T>(f: impl FnOnce(&mut MockState) -> T) -> T {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    f(state.get_or_insert_with(MockState::default))
+}
+
+fn record(path: &str, body: &[u8]) {
+    let call = Call {
+        path: path.to_string(),
+        body: String::from_utf8_lossy(body).to_string(),
+    };
+    with_state(|state| state.calls.push(call));
+}
+
+/// forgets the recorded calls and queued replies
+pub fn reset() {
+    with_state(|state| *state = MockState::default());
+}
+
+/// the next answer of the language model
+pub fn queue_reply(reply: &str) {
+    with_state(|state| state.replies.push_back(reply.to_string()));
+}
+
+pub fn set_transcript(transcript: &str) {
+    with_state(|state| state.transcript = transcript.to_string());
+}
+
+pub fn set_image_delay(delay: std::time::Duration) {
+    with_state(|state| state.image_delay = delay);
+}
+
+/// keeps every txt2img request waiting until release_images
+pub fn hold_images() {
+    with_state(|state| state.held = true);
+}
+
+pub fn release_images() {
+    with_state(|state| state.held = false);
+}
+
+/// txt2img sends PNG from now on
+pub fn send_png() {
+    with_state(|state| state.png = true);
+}
+
+pub fn calls() -> Vec<Call> {
+    with_state(|state| state.calls.clone())
+}
+
+/// the calls whose path ends with suffix, e.g. "/sendMessage",
+/// ignoring case like telegram does for method names
+pub fn calls_to(suffix: &str) -> Vec<Call> {
+    calls()
+        .into_iter()
+        .filter(|call| call.path.to_lowercase().ends_with(&suffix.to_lowercase()))
+        .collect()
+}
+
+/// oobabooga: answers with the queued reply appended to the history it got
+async fn chat(body: Bytes) -> Json<Value> {
+    record("/api/v1/chat", &body);
+    let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let reply =
+        with_state(|state| state.replies.pop_front()).unwrap_or_else(|| DEFAULT_REPLY.to_string());
+    let mut history = request["history"].clone();
+    for key in ["internal", "visible"] {
+        let mut exchanges = history[key].as_array().cloned().unwrap_or_default();
+        exchanges.push(json!([request["user_input"], reply]));
+        history[key] = Value::Array(exchanges);
+    }
+    Json(json!({ "results": [{ "history": history }] }))
+}
+
+/// an openai compatible server with function calling, a queued reply like
+/// {"tool": "weather", "arguments": {...}} is answered as a call of that tool
+async fn completions(body: Bytes) -> Json<Value> {
+    record("/v1/chat/completions", &body);
+    let reply =
+        with_state(|state| state.replies.pop_front()).unwrap_or_else(|| DEFAULT_REPLY.to_string());
+    let message = match serde_json::from_str::<Value>(&reply) {
+        Ok(call) if call.get("tool").is_some() => json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": call["tool"], "arguments": call["arguments"].to_string() },
+            }],
+        }),
+        _ => json!({ "role": "assistant", "content": reply }),
+    };
+    Json(json!({ "choices": [{ "message": message }] }))
+}
+
+async fn txt2img(body: Bytes) -> Json<Value> {
+    record("/sdapi/v1/txt2img", &body);
+    tokio::time::sleep(with_state(|state| state.image_delay)).await;
+    while with_state(|state| state.held) {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    // a picture for every image of the batch, with the seeds counting up
+    let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let count = request["batch_size"].as_i64().unwrap_or(1).max(1);
+    let info = json!({
+        "seed": SEED,
+        "subseed": 99,
+        "sampler_name": "Euler",
+        "sd_model_name": "mock model",
+        "infotexts": (0..count).map(|index| format!("mock parameters {}", index)).collect::<Vec<_>>(),
+        "all_seeds": (0..count).map(|index| SEED + index).collect::<Vec<_>>(),
+    });
+    let image = if with_state(|state| state.png) {
+        PNG
+    } else {
+        IMAGE
+    };
+    Json(json!({
+        "images": vec![general_purpose::STANDARD.encode(image); count as usize],
+        "info": info.to_string(),
+    }))
+}
+
+async fn img2img(body: Bytes) -> Json<Value> {
+    record("/sdapi/v1/img2img", &body);
+    Json(json!({ "images": [general_purpose::STANDARD.encode(IMAGE)] }))
+}
+
+async fn interrupt() -> Json<Value> {
+    record("/sdapi/v1/interrupt", b"");
+    Json(json!({}))
+}
+
+/// the image of the current step the mock progress shows
+pub const PREVIEW: &[u8] = b"mock preview";
+
+/// always halfway
+async fn progress(RawQuery(query): RawQuery) -> Json<Value> {
+    record("/sdapi/v1/progress", query.unwrap_or_default().as_bytes());
+    Json(json!({
+        "progress": 0.5,
+        "eta_relative": 3.2,
+        "state": { "sampling_step": 10, "sampling_steps": 20 },
+        "current_image": general_purpose::STANDARD.encode(PREVIEW),
+    }))
+}
+
+/// what the mock captioners see on every photo
+pub const CAPTION: &str = "a cat sleeping on a sofa";
+
+async fn interrogate(body: Bytes) -> Json<Value> {
+    record("/sdapi/v1/interrogate", &body);
+    Json(json!({ "caption": CAPTION }))
+}
+
+/// ollama style vision model
+async fn generate(body: Bytes) -> Json<Value> {
+    record("/api/generate", &body);
+    Json(json!({ "model": "llava", "response": CAPTION, "done": true }))
+}
+
+async fn extras(body: Bytes) -> Json<Value> {
+    record("/sdapi/v1/extra-single-image", &body);
+    Json(json!({ "image": general_purpose::STANDARD.encode(UPSCALED), "html_info": "" }))
+}
+
+async fn rembg(body: Bytes) -> Vec<u8> {
+    record("/api/remove", &body);
+    CUTOUT.to_vec()
+}
+
+/// huggingface style image classifier, every picture looks nsfw to it
+async fn classify(body: Bytes) -> Json<Value> {
+    record("/classify", &body);
+    Json(json!([{ "label": "nsfw", "score": 0.9 }, { "label": "normal", "score": 0.1 }]))
+}
+
+async fn asr(body: Bytes) -> String {
+    record("/asr", &body);
+    with_state(|state| state.transcript.clone())
+}
+
+async fn tts(body: Bytes) -> Vec<u8> {
+    record("/cognitiveservices/v1", &body);
+    VOICE.to_vec()
+}
+
+/// comfyui queues the workflows as mock-prompt-1, mock-prompt-2 and so on,
+/// /history has them finished right away
+async fn comfy_prompt(body: Bytes) -> Json<Value> {
+    record("/prompt", &body);
+    let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let client = request["client_id"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let number = with_state(|state| {
+        state.comfy_clients.push(client);
+        state.comfy_clients.len()
+    });
+    Json(json!({ "prompt_id": format!("mock-prompt-{}", number), "number": number }))
+}
+
+/// the step comfyui reports for a workflow, 5 of 20 for mock-prompt-1, 10 for the second
+pub fn comfy_step(number: usize) -> u32 {
+    5 * number as u32
+}
+
+/// Comfyui's websocket: once the client queued its workflow, its progress and a preview,
+/// then its end after the image delay, or when the images are released.
+async fn comfy_socket(RawQuery(query): RawQuery, request: Request<Body>) -> Response {
+    record("/ws", query.clone().unwrap_or_default().as_bytes());
+    let client = query
+        .unwrap_or_default()
+        .trim_start_matches("clientId=")
+        .to_string();
+    let key = request
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .map(|key| derive_accept_key(key.as_bytes()))
+        .unwrap_or_default();
+    tokio::spawn(async move {
+        let upgraded = match hyper::upgrade::on(request).await {
+            Ok(upgraded) => upgraded,
+            Err(_) => return,
+        };
+        let mut socket =
+            WebSocketStream::from_raw_socket(TokioAdapter::new(upgraded), Role::Server, None).await;
+        // the client listens before it queues the workflow
+        let mut number = None;
+        for _ in 0..200 {
+            number = with_state(|state| state.comfy_clients.iter().position(|c| *c == client));
+            if number.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let number = match number {
+            Some(index) => index + 1,
+            None => return,
+        };
+        let prompt_id = format!("mock-prompt-{}", number);
+        let event = |kind: &str, mut data: Value| {
+            data["prompt_id"] = json!(prompt_id);
+            Message::Text(json!({ "type": kind, "data": data }).to_string())
+        };
+        let progress = json!({ "value": comfy_step(number), "max": 20 });
+        // a preview is event type 1 and image format 2, a png
+        let mut preview = vec![0, 0, 0, 1, 0, 0, 0, 2];
+        preview.extend(PREVIEW);
+        let _ = socket.send(event("progress", progress)).await;
+        let _ = socket.send(Message::Binary(preview)).await;
+        tokio::time::sleep(with_state(|state| state.image_delay)).await;
+        while with_state(|state| state.held) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let _ = socket
+            .send(event("executing", json!({ "node": null })))
+            .await;
+    });
+    (
+        StatusCode::SWITCHING_PROTOCOLS,
+        [
+            (header::UPGRADE, "websocket".to_string()),
+            (header::CONNECTION, "upgrade".to_string()),
+            (header::SEC_WEBSOCKET_ACCEPT, key),
+        ],
+    )
+        .into_response()
+}
+
+async fn comfy_history(Path(id): Path<String>) -> Json<Value> {
+    record("/history", id.as_bytes());
+    Json(json!({ id: {
+        "status": { "status_str": "success", "completed": true },
+        "outputs": { "9": { "images": [
+            { "filename": "waifu_bot_00001_.png", "subfolder": "", "type": "output" },
+        ] } },
+    } }))
+}
+
+async fn comfy_view(RawQuery(query): RawQuery) -> Vec<u8> {
+    record("/view", query.unwrap_or_default().as_bytes());
+    IMAGE.to_vec()
+}
+
+async fn weather(RawQuery(query): RawQuery) -> Json<Value> {
+    record("/data/2.5/weather", query.unwrap_or_default().as_bytes());
+    Json(json!({
+        "name": "Amsterdam",
+        "main": { "temp": 12.5 },
+        "weather": [{ "description": "light rain" }],
+    }))
+}
+
+/// the appointment the caldav server has every day
+pub const APPOINTMENT: &str = "Dentist";
+
+fn multistatus(responses: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">{}</d:multistatus>"#,
+        responses
+    )
+}
+
+fn dav_response(href: &str, prop: &str) -> String {
+    format!(
+        "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop>\
+         <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+        href, prop
+    )
+}
+
+/// caldav: PROPFIND finds the principal, its calendar home and the calendar in it,
+/// REPORT lists its events, one appointment at 10 today
+async fn caldav(method: Method, uri: Uri, body: Bytes) -> impl IntoResponse {
+    record(&format!("{} {}", method, uri.path()), &body);
+    let request = String::from_utf8_lossy(&body);
+    let xml = if method.as_str() == "REPORT" {
+        let event = format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//mock//caldav//EN\r\nBEGIN:VEVENT\r\n\
+             UID:dentist@mock\r\nDTSTAMP:{today}T080000Z\r\nDTSTART:{today}T100000Z\r\n\
+             DTEND:{today}T110000Z\r\nSUMMARY:{}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+            APPOINTMENT,
+            today = chrono::Utc::now().format("%Y%m%d"),
+        );
+        dav_response(
+            "/caldav/calendars/tester/personal/dentist.ics",
+            &format!(
+                "<d:getetag>\"1\"</d:getetag><c:calendar-data>{}</c:calendar-data>",
+                event
+            ),
+        )
+    } else if request.contains("current-user-principal") {
+        dav_response(
+            uri.path(),
+            "<d:current-user-principal><d:href>/caldav/principals/tester/</d:href>\
+             </d:current-user-principal>",
+        )
+    } else if request.contains("calendar-home-set") {
+        dav_response(
+            uri.path(),
+            "<c:calendar-home-set><d:href>/caldav/calendars/tester/</d:href></c:calendar-home-set>",
+        )
+    } else {
+        dav_response(
+            "/caldav/calendars/tester/",
+            "<d:resourcetype><d:collection/></d:resourcetype>",
+        ) + &dav_response(
+            "/caldav/calendars/tester/personal/",
+            "<d:displayname>personal</d:displayname>\
+             <d:resourcetype><d:collection/><c:calendar/></d:resourcetype>\
+             <c:supported-calendar-component-set><c:comp name=\"VEVENT\"/>\
+             </c:supported-calendar-component-set>",
+        )
+    };
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        multistatus(&xml),
+    )
+}
+
+/// telegram bot api: every method answers with a message, which is what the send methods expect
+async fn telegram(uri: Uri, body: Bytes) -> Json<Value> {
+    record(uri.path(), &body);
+    Json(json!({
+        "ok": true,
+        "result": {
+            "message_id": 1,
+            "date": 0,
+            "chat": { "id": 1, "type": "private", "first_name": "test" },
+            "from": { "id": 2, "is_bot": true, "first_name": "waifu", "username": "waifu_bot" },
+            "text": "sent",
+        },
+    }))
+}
+
+/// starts the mock backends on a random port in a thread of their own,
+/// so they outlive the runtime of a single test, returns their url
+pub fn start() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = Router::new()
+        .route("/api/v1/chat", post(chat))
+        .route("/v1/chat/completions", post(completions))
+        .route("/sdapi/v1/txt2img", post(txt2img))
+        .route("/sdapi/v1/img2img", post(img2img))
+        .route("/sdapi/v1/interrogate", post(interrogate))
+        .route("/sdapi/v1/extra-single-image", post(extras))
+        .route("/api/remove", post(rembg))
+        .route("/sdapi/v1/progress", get(progress))
+        .route("/sdapi/v1/interrupt", post(interrupt))
+        .route("/api/generate", post(generate))
+        .route("/prompt", post(comfy_prompt))
+        .route("/history/:id", get(comfy_history))
+        .route("/view", get(comfy_view))
+        .route("/ws", get(comfy_socket))
+        .route("/classify", post(classify))
+        .route("/asr", post(asr))
+        .route("/cognitiveservices/v1", post(tts))
+        .route("/data/2.5/weather", get(weather))
+        .route("/caldav/*path", any(caldav))
+        .fallback(telegram);
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+    });
+    url
+}
@@ -0,0 +1,215 @@
+//! End to end tests: whole conversations run against the mock backends
+//! and the tests check what the character sent.
+
+mod conversation;
+pub mod mock;
+
+use std::sync::{Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use ini::Ini;
+use oobabooga_rs::History;
+
+use crate::transport::{Button, Picture, Transport};
+
+const CONFIG: &str = "./config/config.ini";
+
+/// the tests share the working directory, config and mock backends, so they run one at a time
+static LOCK: Mutex<()> = Mutex::new(());
+static MOCK_URL: Mutex<Option<String>> = Mutex::new(None);
+
+pub struct TestEnv {
+    pub url: String,
+    _lock: MutexGuard<'static, ()>,
+}
+
+/// Moves into a scratch directory with a config pointing every backend at the mocks.
+/// Config, histories and recorded calls are fresh for every test.
+pub fn setup() -> TestEnv {
+    let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let url = MOCK_URL
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(|| {
+            let dir = std::env::temp_dir().join(format!("waifu_bot_test_{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("config")).unwrap();
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::env::set_current_dir(&dir).unwrap();
+            mock::start()
+        })
+        .clone();
+    for entry in std::fs::read_dir(".").unwrap().flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let generated = [
+            "history_",
+            "last_message_",
+            "last_prompt_",
+            "last_image_",
+            "scene_",
+            "image_jobs",
+            "image_records",
+        ];
+        if generated.iter().any(|prefix| name.starts_with(prefix)) {
+            std::fs::remove_file(entry.path()).unwrap();
+        }
+    }
+    std::fs::write(
+        CONFIG,
+        format!(
+            "[chat_ai]
+url = {url}
+character = test
+your_name = tester
+[sd_ai]
+enabled = true
+url = {url}
+lora =
+negative_promt =
+positive_promt =
+[telegram]
+token = 123:test
+user = tester
+parse_mode = plain
+[calendar]
+enabled = false
+[tts]
+enabled = false
+[azure]
+key = test
+region = test
+url = {url}
+[whisper]
+url = {url}
+[openweather]
+url = {url}
+token = test
+[huggingface]
+token =
+mood = false
+[time]
+enabled = false
+[log]
+level = error
+",
+            url = url
+        ),
+    )
+    .unwrap();
+    mock::reset();
+    TestEnv { url, _lock: lock }
+}
+
+pub fn set_config(section: &str, key: &str, value: &str) {
+    let mut conf = Ini::load_from_file(CONFIG).unwrap();
+    conf.with_section(Some(section)).set(key, value);
+    conf.write_to_file(CONFIG).unwrap();
+}
+
+pub fn empty_history() -> History {
+    History {
+        internal: vec![],
+        visible: vec![],
+    }
+}
+
+/// what the character sent, files are read right away since the next generation overwrites them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sent {
+    Text(String),
+    Image(Vec<u8>),
+    Voice(Vec<u8>),
+    Sticker(String),
+    /// a status message was changed
+    Edit(String),
+    Preview(Vec<u8>),
+    /// a picture behind a spoiler
+    Spoiler(Vec<u8>),
+}
+
+#[derive(Default)]
+pub struct RecordingTransport {
+    pub conversation: Option<String>,
+    sent: Mutex<Vec<Sent>>,
+    buttons: Mutex<Vec<Vec<Button>>>,
+}
+
+impl RecordingTransport {
+    pub fn sent(&self) -> Vec<Sent> {
+        self.sent.lock().unwrap().clone()
+    }
+    /// the buttons under every picture
+    pub fn buttons(&self) -> Vec<Vec<Button>> {
+        self.buttons.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Transport for RecordingTransport {
+    async fn send_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.sent.lock().unwrap().push(Sent::Text(text.to_string()));
+        Ok(())
+    }
+    async fn send_image(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let data = std::fs::read(path)?;
+        self.sent.lock().unwrap().push(Sent::Image(data));
+        Ok(())
+    }
+    async fn send_voice(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let data = std::fs::read(path)?;
+        self.sent.lock().unwrap().push(Sent::Voice(data));
+        Ok(())
+    }
+    async fn send_sticker(
+        &self,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push(Sent::Sticker(path.to_string()));
+        Ok(())
+    }
+    async fn send_status(
+        &self,
+        text: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        self.send_text(text).await?;
+        Ok(Some("status".to_string()))
+    }
+    async fn edit_status(
+        &self,
+        _id: &str,
+        text: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.sent.lock().unwrap().push(Sent::Edit(text.to_string()));
+        Ok(())
+    }
+    async fn send_preview(
+        &self,
+        _previous: Option<&str>,
+        path: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let data = std::fs::read(path)?;
+        self.sent.lock().unwrap().push(Sent::Preview(data));
+        Ok(Some("preview".to_string()))
+    }
+    async fn send_picture(
+        &self,
+        picture: &Picture,
+        preview: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.buttons.lock().unwrap().push(picture.buttons.clone());
+        if picture.spoiler {
+            let data = std::fs::read(&picture.path)?;
+            self.sent.lock().unwrap().push(Sent::Spoiler(data));
+            return Ok(());
+        }
+        match preview {
+            Some(id) => self.send_preview(Some(id), &picture.path).await.map(|_| ()),
+            None => self.send_image(&picture.path).await,
+        }
+    }
+    fn conversation(&self) -> Option<String> {
+        self.conversation.clone()
+    }
+}
@@ -0,0 +1,247 @@
+use std::{cell::RefCell, collections::VecDeque, fs, future::Future, io::Write, panic};
+
+use chrono::{DateTime, Utc};
+use futures_util::FutureExt;
+use oobabooga_rs::History;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::get_ini_value;
+
+/// The message a trace was recorded for, the first line of every trace.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub text: String,
+    /// the message was a voice message, text is what whisper heard
+    pub voice: bool,
+    pub history: History,
+    pub conversation: Option<String>,
+    pub time: DateTime<Utc>,
+    /// the message came with a photo, this is what the character saw on it
+    #[serde(default)]
+    pub photo: Option<String>,
+}
+
+impl Message {
+    pub fn new(text: &str, voice: bool, history: &History, conversation: Option<String>) -> Self {
+        Message {
+            text: text.to_string(),
+            voice,
+            history: history.clone(),
+            conversation,
+            time: Utc::now(),
+            photo: None,
+        }
+    }
+}
+
+/// one call to a backend, e.g. kind "chat" is a request to oobabooga and what it answered
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Exchange {
+    pub kind: String,
+    pub request: Value,
+    pub response: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line {
+    Message(Message),
+    Exchange(Exchange),
+}
+
+enum Mode {
+    Record {
+        message: Message,
+        exchanges: Vec<Exchange>,
+    },
+    Replay(VecDeque<Exchange>),
+}
+
+tokio::task_local! {
+    static TRACE: RefCell<Mode>;
+}
+
+pub fn trace_enabled() -> bool {
+    get_ini_value("trace", "enabled").unwrap_or_default() == "true"
+}
+
+fn active() -> bool {
+    TRACE.try_with(|_| ()).is_ok()
+}
+
+/// Runs the reply to a message while recording every backend exchange,
+/// then writes them to a new trace file. Does nothing when already recording or replaying.
+pub async fn record<F: Future>(message: Message, f: F) -> F::Output {
+    if !trace_enabled() || active() {
+        return f.await;
+    }
+    let mode = RefCell::new(Mode::Record {
+        message,
+        exchanges: vec![],
+    });
+    TRACE
+        .scope(mode, async {
+            let output = f.await;
+            TRACE.with(|mode| {
+                if let Mode::Record { message, exchanges } = &*mode.borrow() {
+                    if let Err(e) = write_trace(message, exchanges) {
+                        log::error!("could not write trace {:?}", e);
+                    }
+                }
+            });
+            output
+        })
+        .await
+}
+
+/// the text of a voice message is only known once whisper has heard it
+pub fn set_text(text: &str) {
+    let _ = TRACE.try_with(|mode| {
+        if let Mode::Record { message, .. } = &mut *mode.borrow_mut() {
+            message.text = text.to_string();
+        }
+    });
+}
+
+/// Runs f with every backend answering from the recorded exchanges. Fails when f calls
+/// a backend the trace has no exchange left for, instead of asking the real one.
+pub async fn replay<F: Future>(exchanges: Vec<Exchange>, f: F) -> Result<F::Output, String> {
+    let replaying = panic::AssertUnwindSafe(f).catch_unwind();
+    TRACE
+        .scope(RefCell::new(Mode::Replay(exchanges.into())), replaying)
+        .await
+        .map_err(|e| match e.downcast::<String>() {
+            Ok(message) => *message,
+            Err(e) => panic::resume_unwind(e),
+        })
+}
+
+fn write_trace(
+    message: &Message,
+    exchanges: &[Exchange],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let dir = get_ini_value("trace", "dir").unwrap_or("./traces".to_string());
+    fs::create_dir_all(&dir)?;
+    let path = format!("{}/{}.jsonl", dir, message.time.format("%Y%m%d_%H%M%S%.3f"));
+    let mut file = fs::File::create(&path)?;
+    writeln!(
+        file,
+        "{}",
+        serde_json::to_string(&Line::Message(message.clone()))?
+    )?;
+    for exchange in exchanges {
+        writeln!(
+            file,
+            "{}",
+            serde_json::to_string(&Line::Exchange(exchange.clone()))?
+        )?;
+    }
+    log::info!("trace written to {}", path);
+    Ok(())
+}
+
+pub fn read_trace(
+    path: &str,
+) -> Result<(Message, Vec<Exchange>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut message = None;
+    let mut exchanges = vec![];
+    for line in fs::read_to_string(path)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line)? {
+            Line::Message(m) => message = Some(m),
+            Line::Exchange(e) => exchanges.push(e),
+        }
+    }
+    match message {
+        Some(message) => Ok((message, exchanges)),
+        None => Err(format!("no message in trace {}", path).into()),
+    }
+}
+
+/// the recorded response of the next exchange of this kind when replaying
+fn replayed(kind: &str) -> Option<Value> {
+    let replaying = TRACE
+        .try_with(|mode| match &mut *mode.borrow_mut() {
+            Mode::Replay(exchanges) => {
+                let index = exchanges.iter().position(|e| e.kind == kind);
+                Some(index.and_then(|index| exchanges.remove(index)))
+            }
+            Mode::Record { .. } => None,
+        })
+        .ok()
+        .flatten();
+    match replaying {
+        Some(Some(exchange)) => Some(exchange.response),
+        // ends the replay, see replay
+        Some(None) => panic::panic_any(format!("no recorded {} exchange left in the trace", kind)),
+        None => None,
+    }
+}
+
+fn recorded<T: Serialize>(kind: &str, request: Value, response: &T) {
+    let _ = TRACE.try_with(|mode| {
+        if let Mode::Record { exchanges, .. } = &mut *mode.borrow_mut() {
+            exchanges.push(Exchange {
+                kind: kind.to_string(),
+                request,
+                response: serde_json::to_value(response).unwrap_or_default(),
+            });
+        }
+    });
+}
+
+fn from_recording<T: DeserializeOwned>(kind: &str) -> Option<T> {
+    let response = replayed(kind)?;
+    match serde_json::from_value(response) {
+        Ok(response) => Some(response),
+        Err(e) => panic::panic_any(format!("recorded {} exchange does not fit: {}", kind, e)),
+    }
+}
+
+/// Calls a backend, recording the exchange or answering from the recording.
+pub async fn exchange<T, F>(kind: &str, request: Value, call: F) -> T
+where
+    T: Serialize + DeserializeOwned,
+    F: Future<Output = T>,
+{
+    if let Some(response) = from_recording(kind) {
+        return response;
+    }
+    let response = call.await;
+    recorded(kind, request, &response);
+    response
+}
+
+/// exchange for backends that are called without await
+pub fn exchange_sync<T, F>(kind: &str, request: Value, call: F) -> T
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> T,
+{
+    if let Some(response) = from_recording(kind) {
+        return response;
+    }
+    let response = call();
+    recorded(kind, request, &response);
+    response
+}
+
+/// exchange for backends that fail with an error box, the error is kept as its message
+pub async fn exchange_result<T, F>(
+    kind: &str,
+    request: Value,
+    call: F,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>>
+where
+    T: Serialize + DeserializeOwned,
+    F: Future<Output = Result<T, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let result: Result<T, String> = exchange(kind, request, async {
+        call.await.map_err(|e| e.to_string())
+    })
+    .await;
+    result.map_err(|e| e.into())
+}
@@ -0,0 +1,281 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use oobabooga_rs::History;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    ai::vision::vision_enabled,
+    character,
+    config::get_ini_value,
+    history,
+    message_parsers::user_asked_for_edit,
+    pipeline::{ai_reply, edit_reply, handle_command, photo_reply},
+    transport::Transport,
+};
+
+pub fn api_enabled() -> bool {
+    get_ini_value("api", "enabled").unwrap_or_default() == "true"
+}
+
+/// Collects everything the pipeline sends, so it can be returned in the http response.
+/// Without a conversation it shares the history with telegram.
+pub struct ApiTransport {
+    pub conversation: Option<String>,
+    pub texts: std::sync::Mutex<Vec<String>>,
+    /// base64 encoded images
+    pub images: std::sync::Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl Transport for ApiTransport {
+    async fn send_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.texts.lock().unwrap().push(text.to_string());
+        Ok(())
+    }
+    async fn send_image(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let data = tokio::fs::read(path).await?;
+        self.images
+            .lock()
+            .unwrap()
+            .push(general_purpose::STANDARD.encode(data));
+        Ok(())
+    }
+    async fn send_voice(
+        &self,
+        _path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(())
+    }
+    async fn send_sticker(
+        &self,
+        _path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(())
+    }
+    fn conversation(&self) -> Option<String> {
+        self.conversation.clone()
+    }
+}
+
+struct ApiState {
+    key: String,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn error(status: StatusCode, message: &str) -> ApiError {
+    (status, Json(json!({ "error": { "message": message } })))
+}
+
+/// accepts `Authorization: Bearer <key>` like openai clients send, or `X-Api-Key: <key>`
+fn authorize(state: &ApiState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let key = header("authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or(header("x-api-key"));
+    // compared in constant time, so the time of a wrong answer does not give the key away
+    let valid = key.is_some_and(|key| key.as_bytes().ct_eq(state.key.as_bytes()).into());
+    if valid {
+        Ok(())
+    } else {
+        Err(error(StatusCode::UNAUTHORIZED, "invalid api key"))
+    }
+}
+
+/// runs a message through the same pipeline as telegram, returns what the character sent
+/// images are a photo to look at or edit, and optionally its inpainting mask
+async fn chat_with_character(
+    message: &str,
+    images: &[Vec<u8>],
+    conversation: Option<String>,
+) -> Result<ApiTransport, ApiError> {
+    let _history = history::file::lock(conversation.as_deref()).await;
+    let transport = ApiTransport {
+        conversation,
+        texts: std::sync::Mutex::new(vec![]),
+        images: std::sync::Mutex::new(vec![]),
+    };
+    let message = message.trim();
+    if message.starts_with('/') {
+        return match handle_command(&transport, message).await {
+            Ok(true) => Ok(transport),
+            Ok(false) => Err(error(StatusCode::BAD_REQUEST, "unknown command")),
+            Err(e) => {
+                log::error!("{}", e);
+                Err(error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))
+            }
+        };
+    }
+    if !images.is_empty() && user_asked_for_edit(message) {
+        let mask = images.get(1).map(|mask| mask.as_slice());
+        return match edit_reply(&transport, message, &images[0], mask).await {
+            Ok(_) => Ok(transport),
+            Err(e) => {
+                log::error!("Error: {}", e);
+                Err(error(StatusCode::BAD_GATEWAY, &e.to_string()))
+            }
+        };
+    }
+    let history =
+        history::file::read_json_from_file(transport.conversation.as_deref()).unwrap_or(History {
+            internal: vec![],
+            visible: vec![],
+        });
+    let res = if !images.is_empty() && vision_enabled() {
+        photo_reply(&transport, message, &images[0], history).await
+    } else {
+        ai_reply(&transport, message, history).await
+    };
+    match res {
+        Ok(_) => Ok(transport),
+        Err(e) => {
+            log::error!("Error: {}", e);
+            Err(error(StatusCode::BAD_GATEWAY, &e.to_string()))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChatBody {
+    message: String,
+    /// base64 photo she looks at or edits as the message asks, a second image is the inpainting mask
+    #[serde(default)]
+    images: Vec<String>,
+    /// separate history, e.g. per device, leave out to share the telegram history
+    conversation: Option<String>,
+}
+
+async fn chat(
+    State(state): State<Arc<ApiState>>,
+    headers: HeaderMap,
+    Json(body): Json<ChatBody>,
+) -> Result<Json<Value>, ApiError> {
+    authorize(&state, &headers)?;
+    let mut images = vec![];
+    for image in &body.images {
+        match general_purpose::STANDARD.decode(image) {
+            Ok(image) => images.push(image),
+            Err(_) => return Err(error(StatusCode::BAD_REQUEST, "images must be base64")),
+        }
+    }
+    let transport = chat_with_character(&body.message, &images, body.conversation).await?;
+    let texts = transport.texts.lock().unwrap().clone();
+    let images = transport.images.lock().unwrap().clone();
+    Ok(Json(json!({
+        "reply": texts.last().cloned().unwrap_or_default(),
+        "messages": texts,
+        "images": images,
+    })))
+}
+
+#[derive(Deserialize)]
+struct CompletionMessage {
+    role: String,
+    #[serde(default)]
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct CompletionBody {
+    #[serde(default)]
+    messages: Vec<CompletionMessage>,
+    /// openai clients send the chat id here, it picks the history
+    user: Option<String>,
+}
+
+/// Only the last user message is used, the bot keeps the history itself
+/// so every client talks to the same character with the same memory.
+async fn completions(
+    State(state): State<Arc<ApiState>>,
+    headers: HeaderMap,
+    Json(body): Json<CompletionBody>,
+) -> Result<Json<Value>, ApiError> {
+    authorize(&state, &headers)?;
+    let message = match body.messages.iter().rev().find(|m| m.role == "user") {
+        Some(message) => message.content.clone(),
+        None => return Err(error(StatusCode::BAD_REQUEST, "no user message")),
+    };
+    let transport = chat_with_character(&message, &[], body.user).await?;
+    let reply = transport
+        .texts
+        .lock()
+        .unwrap()
+        .last()
+        .cloned()
+        .unwrap_or_default();
+    let now = Utc::now();
+    Ok(Json(json!({
+        "id": format!("chatcmpl-{}", now.timestamp_millis()),
+        "object": "chat.completion",
+        "created": now.timestamp(),
+        "model": model_name(),
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": reply },
+            "finish_reason": "stop",
+        }],
+        "usage": { "prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0 },
+    })))
+}
+
+fn model_name() -> String {
+    match character::load_character() {
+        Some(card) => card.name,
+        None => get_ini_value("chat_ai", "character").unwrap_or_default(),
+    }
+}
+
+async fn models(
+    State(state): State<Arc<ApiState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    authorize(&state, &headers)?;
+    Ok(Json(json!({
+        "object": "list",
+        "data": [{ "id": model_name(), "object": "model", "owned_by": "waifu_bot" }],
+    })))
+}
+
+/// the api routes, clients have to send key
+pub fn app(key: String) -> Router {
+    Router::new()
+        .route("/api/chat", post(chat))
+        .route("/v1/chat/completions", post(completions))
+        .route("/v1/models", get(models))
+        .with_state(Arc::new(ApiState { key }))
+}
+
+/// serves the character over http, for other tools that want to talk to her
+pub async fn run() {
+    let key = get_ini_value("api", "key").unwrap_or_default();
+    if key.is_empty() {
+        log::error!("No api key set, not starting the api");
+        return;
+    }
+    let address = get_ini_value("api", "address").unwrap_or("127.0.0.1:5005".to_string());
+    let address: SocketAddr = match address.parse() {
+        Ok(address) => address,
+        Err(e) => {
+            log::error!("invalid api address {}: {:?}", address, e);
+            return;
+        }
+    };
+    log::info!("api listening on {}", address);
+    if let Err(e) = axum::Server::bind(&address)
+        .serve(app(key).into_make_service())
+        .await
+    {
+        log::error!("api stopped {:?}", e);
+    }
+}
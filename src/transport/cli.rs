@@ -0,0 +1,201 @@
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use crate::{
+    character,
+    config::get_ini_value,
+    history,
+    pipeline::{ai_reply, described_photo_reply, handle_command, voice_reply},
+    trace,
+    transport::Transport,
+};
+
+/// Talks to the character from a terminal.
+/// Images, voice and stickers are copied to out_dir instead of being shown.
+pub struct CliTransport {
+    pub out_dir: PathBuf,
+    pub conversation: Option<String>,
+}
+
+impl CliTransport {
+    pub fn new() -> Self {
+        let out_dir =
+            PathBuf::from(get_ini_value("cli", "out_dir").unwrap_or("./out/cli".to_string()));
+        if let Err(e) = std::fs::create_dir_all(&out_dir) {
+            log::error!("could not create {:?}: {:?}", out_dir, e);
+        }
+        CliTransport {
+            out_dir,
+            conversation: None,
+        }
+    }
+
+    /// copies a generated file so the next generation doesn't overwrite it
+    fn save_copy(
+        &self,
+        path: &str,
+        kind: &str,
+    ) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        let extension = PathBuf::from(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let destination = self.out_dir.join(format!(
+            "{}_{}.{}",
+            kind,
+            Utc::now().format("%Y%m%d_%H%M%S%.3f"),
+            extension
+        ));
+        std::fs::copy(path, &destination)?;
+        Ok(destination)
+    }
+}
+
+#[async_trait]
+impl Transport for CliTransport {
+    async fn send_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let character = match character::load_character() {
+            Some(card) => card.name,
+            None => get_ini_value("chat_ai", "character").unwrap_or_default(),
+        };
+        println!("{}: {}", character, text);
+        Ok(())
+    }
+    async fn send_image(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let saved = self.save_copy(path, "image")?;
+        println!("[image saved to {}]", saved.display());
+        Ok(())
+    }
+    async fn send_voice(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let saved = self.save_copy(path, "voice")?;
+        println!("[voice saved to {}]", saved.display());
+        Ok(())
+    }
+    async fn send_sticker(
+        &self,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        println!("[sticker {}]", path);
+        Ok(())
+    }
+    fn conversation(&self) -> Option<String> {
+        self.conversation.clone()
+    }
+}
+
+/// runs the message pipeline in a terminal repl, for development without telegram
+pub async fn run() {
+    let transport = CliTransport::new();
+    println!("chatting from the terminal, /quit to stop");
+    let stdin = io::stdin();
+    loop {
+        print!(
+            "{}: ",
+            get_ini_value("chat_ai", "your_name").unwrap_or("you".to_string())
+        );
+        io::stdout().flush().ok();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("could not read input {:?}", e);
+                break;
+            }
+        }
+        let text = line.trim();
+        if text.is_empty() {
+            continue;
+        }
+        if text == "/quit" || text == "/exit" {
+            break;
+        }
+        if text.starts_with('/') {
+            let res = handle_command(&transport, text).await;
+            match res {
+                Ok(true) => {}
+                Ok(false) => {
+                    if let Some(path) = text.strip_prefix("/import_character ") {
+                        import_character(path.trim());
+                    } else {
+                        println!("unknown command {}", text);
+                    }
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                }
+            }
+            continue;
+        }
+        let history = history::file::read_json_from_file(None).unwrap_or(oobabooga_rs::History {
+            internal: vec![],
+            visible: vec![],
+        });
+        if let Err(e) = ai_reply(&transport, text, history).await {
+            log::error!("Error: {}", e);
+        }
+    }
+}
+
+/// Runs a recorded message again, every backend answers what it answered then.
+/// Uses a history of its own, so the real history is left alone.
+pub async fn replay(path: &str) {
+    let (message, exchanges) = match trace::read_trace(path) {
+        Ok(trace) => trace,
+        Err(e) => {
+            log::error!("could not read trace {}: {}", path, e);
+            return;
+        }
+    };
+    let mut transport = CliTransport::new();
+    transport.conversation = Some("replay".to_string());
+    println!(
+        "replaying {} from {} ({} exchanges)",
+        if message.voice {
+            "a voice message"
+        } else if message.photo.is_some() {
+            "a photo"
+        } else {
+            "a message"
+        },
+        message.time,
+        exchanges.len()
+    );
+    println!(
+        "{}: {}",
+        get_ini_value("chat_ai", "your_name").unwrap_or("you".to_string()),
+        message.text
+    );
+    trace::replay(exchanges, async {
+        let res = match (&message.photo, message.voice) {
+            (Some(photo), _) => {
+                described_photo_reply(&transport, &message.text, photo, message.history).await
+            }
+            (None, true) => {
+                // whisper is replayed, so the audio itself is not needed
+                voice_reply(&transport, vec![], message.history).await;
+                Ok(())
+            }
+            (None, false) => ai_reply(&transport, &message.text, message.history).await,
+        };
+        if let Err(e) = res {
+            log::error!("Error: {}", e);
+        }
+    })
+    .await
+    .unwrap_or_else(|e| log::error!("replay failed: {}", e));
+}
+
+fn import_character(path: &str) {
+    let res = std::fs::read(path)
+        .map_err(|e| e.into())
+        .and_then(|data| character::parse_card(&data))
+        .and_then(|card| character::save_character(&card).map(|_| card));
+    match res {
+        Ok(card) => println!("{} has been imported.", card.name),
+        Err(e) => println!("could not import character: {}", e),
+    }
+}
@@ -0,0 +1,318 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use oobabooga_rs::History;
+use serenity::{
+    http::Http,
+    model::{
+        channel::{Attachment, AttachmentType, Message},
+        gateway::Ready,
+        id::{ChannelId, MessageId},
+    },
+    prelude::*,
+};
+
+use crate::{
+    ai::{image_tools::Tool, vision::vision_enabled},
+    character,
+    config::get_ini_value,
+    formatting, history,
+    message_parsers::user_asked_for_edit,
+    pipeline::{ai_reply, edit_reply, handle_command, image_tool, photo_reply, queue, voice_reply},
+    transport::{Picture, Transport},
+};
+
+/// discord refuses messages longer than this
+pub const DISCORD_MAX_LENGTH: usize = 2000;
+
+pub fn discord_enabled() -> bool {
+    get_ini_value("discord", "enabled").unwrap_or_default() == "true"
+}
+
+/// a discord channel the character talks in, every channel has its own history
+pub struct DiscordTransport {
+    pub http: Arc<Http>,
+    pub channel_id: ChannelId,
+}
+
+impl DiscordTransport {
+    async fn send_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.channel_id
+            .send_files(&self.http, vec![path], |m| m)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transport for DiscordTransport {
+    /// discord renders markdown itself, so the text only has to be split
+    async fn send_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for part in formatting::split_message(text, DISCORD_MAX_LENGTH) {
+            self.channel_id.say(&self.http, part).await?;
+        }
+        Ok(())
+    }
+    async fn send_image(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.send_file(path).await
+    }
+    async fn send_status(
+        &self,
+        text: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let message = self.channel_id.say(&self.http, text).await?;
+        Ok(Some(message.id.0.to_string()))
+    }
+    async fn edit_status(
+        &self,
+        id: &str,
+        text: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.channel_id
+            .edit_message(&self.http, MessageId(id.parse()?), |m| m.content(text))
+            .await?;
+        Ok(())
+    }
+    /// discord hides attachments whose name starts with SPOILER_
+    async fn send_picture(
+        &self,
+        picture: &Picture,
+        _preview: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !picture.spoiler {
+            return self.send_file(&picture.path).await;
+        }
+        let file = AttachmentType::Bytes {
+            data: std::fs::read(&picture.path)?.into(),
+            filename: "SPOILER_picture.png".to_string(),
+        };
+        self.channel_id
+            .send_files(&self.http, vec![file], |m| m)
+            .await?;
+        Ok(())
+    }
+    async fn send_voice(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.send_file(path).await
+    }
+    async fn send_sticker(
+        &self,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.send_file(path).await
+    }
+    fn conversation(&self) -> Option<String> {
+        Some(format!("discord_{}", self.channel_id))
+    }
+    fn address(&self) -> Option<String> {
+        Some(format!("discord:{}", self.channel_id))
+    }
+}
+
+/// comma separated list from the discord section, empty means nothing is allowed
+fn allowed(key: &str, value: &str) -> bool {
+    let list = get_ini_value("discord", key).unwrap_or_default();
+    !value.is_empty() && list.split(',').any(|item| item.trim() == value)
+}
+
+struct Handler;
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, _ctx: Context, ready: Ready) {
+        log::info!("connected to discord as {}", ready.user.name);
+    }
+
+    async fn message(&self, ctx: Context, msg: Message) {
+        // only answer the configured users, and never other bots (or herself)
+        if msg.author.bot
+            || !(allowed("users", &msg.author.name) || allowed("users", &msg.author.id.to_string()))
+            || !allowed("channels", &msg.channel_id.to_string())
+        {
+            return;
+        }
+        let transport = DiscordTransport {
+            http: ctx.http.clone(),
+            channel_id: msg.channel_id,
+        };
+        let conversation = transport.conversation();
+        let _history = history::file::lock(conversation.as_deref()).await;
+        let history =
+            history::file::read_json_from_file(conversation.as_deref()).unwrap_or(History {
+                internal: vec![],
+                visible: vec![],
+            });
+
+        if let Some(attachment) = msg.attachments.first() {
+            if msg.content.trim() == "/import_character" {
+                let reply = match import_character(attachment).await {
+                    Ok(name) => format!("{} has been imported.", name),
+                    Err(e) => {
+                        log::error!("could not import character {:?}", e);
+                        format!("could not import character: {}", e)
+                    }
+                };
+                if let Err(e) = transport.send_text(&reply).await {
+                    log::error!("{:?}", e);
+                }
+                return;
+            }
+            let is_audio = attachment
+                .content_type
+                .as_deref()
+                .unwrap_or_default()
+                .starts_with("audio/");
+            if is_audio {
+                match attachment.download().await {
+                    Ok(data) => {
+                        log::info!("audio downloaded");
+                        voice_reply(&transport, data, history).await;
+                    }
+                    Err(e) => {
+                        log::error!("Error downloading file {:?}", e);
+                    }
+                }
+                return;
+            }
+            // a photo and optionally its inpainting mask, with e.g. "make this anime style"
+            let images: Vec<&Attachment> = msg
+                .attachments
+                .iter()
+                .filter(|attachment| {
+                    attachment
+                        .content_type
+                        .as_deref()
+                        .unwrap_or_default()
+                        .starts_with("image/")
+                })
+                .collect();
+            if !images.is_empty() && Tool::from_command(&msg.content).is_some() {
+                let res = match images[0].download().await {
+                    Ok(image) => image_tool(&transport, &msg.content, Some(&image)).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = res {
+                    log::error!("could not change photo {:?}", e);
+                }
+                return;
+            }
+            if !images.is_empty() && user_asked_for_edit(&msg.content) {
+                if let Err(e) = edit_attachments(&transport, &msg.content, &images).await {
+                    log::error!("could not edit photo {:?}", e);
+                }
+                return;
+            }
+            if !images.is_empty() && vision_enabled() {
+                let res = match images[0].download().await {
+                    Ok(image) => photo_reply(&transport, &msg.content, &image, history).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = res {
+                    log::error!("could not look at photo {:?}", e);
+                }
+                return;
+            }
+            log::info!("attachment received {:?}", attachment.filename);
+        }
+
+        let text = msg.content.trim();
+        if text.is_empty() {
+            return;
+        }
+        if text.starts_with('/') {
+            match handle_command(&transport, text).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    if text == "/import_character" {
+                        if let Err(e) = transport.send_text("Send the character card (json or png) as an attachment with /import_character as message.").await {
+                            log::error!("{:?}", e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                }
+            }
+            return;
+        }
+        if let Err(e) = msg.channel_id.broadcast_typing(&ctx.http).await {
+            log::debug!("could not show typing {:?}", e);
+        }
+        match ai_reply(&transport, text, history).await {
+            Ok(_) => {
+                log::info!("ai has replied")
+            }
+            Err(e) => {
+                log::error!("Error: {}", e)
+            }
+        }
+    }
+}
+
+/// downloads the picture (and optional mask) and edits it with the caption
+async fn edit_attachments(
+    transport: &DiscordTransport,
+    caption: &str,
+    images: &[&Attachment],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let image = images[0].download().await?;
+    let mask = match images.get(1) {
+        Some(mask) => Some(mask.download().await?),
+        None => None,
+    };
+    edit_reply(transport, caption, &image, mask.as_deref()).await
+}
+
+/// downloads a character card and makes it the character the bot uses
+async fn import_character(
+    attachment: &Attachment,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let data = attachment.download().await?;
+    let character = character::parse_card(&data)?;
+    character::save_character(&character)?;
+    Ok(character.name)
+}
+
+pub async fn run() {
+    let token = get_ini_value("discord", "token").unwrap_or_default();
+    if token.is_empty() {
+        log::error!("No discord token found");
+        return;
+    }
+    for key in ["users", "channels"] {
+        if get_ini_value("discord", key)
+            .unwrap_or_default()
+            .trim()
+            .is_empty()
+        {
+            log::warn!("discord {} is empty, the bot will not answer anyone", key);
+        }
+    }
+    let intents = GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::DIRECT_MESSAGES
+        | GatewayIntents::MESSAGE_CONTENT;
+    let client = Client::builder(&token, intents)
+        .event_handler(Handler)
+        .await;
+    match client {
+        Ok(mut client) => {
+            // once before connecting, ready comes again on every reconnect
+            let http = client.cache_and_http.http.clone();
+            tokio::spawn(async move {
+                queue::resume("discord", |address| {
+                    let channel_id = address.strip_prefix("discord:")?.parse().ok()?;
+                    Some(Box::new(DiscordTransport {
+                        http: http.clone(),
+                        channel_id: ChannelId(channel_id),
+                    }))
+                })
+                .await
+            });
+            if let Err(e) = client.start().await {
+                log::error!("discord client stopped {:?}", e);
+            }
+        }
+        Err(e) => {
+            log::error!("could not create discord client {:?}", e);
+        }
+    }
+}
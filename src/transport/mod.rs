@@ -0,0 +1,95 @@
+use async_trait::async_trait;
+
+pub mod api;
+pub mod cli;
+pub mod discord;
+pub mod telegram;
+
+/// a button under a picture, the frontend hands its action to pipeline::image_action when pressed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Button {
+    pub label: String,
+    pub action: String,
+}
+
+/// a finished picture and the buttons under it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Picture {
+    pub path: String,
+    pub buttons: Vec<Button>,
+    /// hidden behind a spoiler by the safety policy of the chat
+    pub spoiler: bool,
+}
+
+/// A frontend the character talks through.
+/// The pipeline only uses this, so it doesn't have to know about telegram.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    /// sends the image at path
+    async fn send_image(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    /// sends the audio at path as a voice message
+    async fn send_voice(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    async fn send_sticker(
+        &self,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    /// Sends a message that can be changed later, e.g. the progress of a picture.
+    /// Returns its id, None when the frontend cannot edit messages.
+    async fn send_status(
+        &self,
+        text: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        self.send_text(text).await?;
+        Ok(None)
+    }
+    async fn edit_status(
+        &self,
+        _id: &str,
+        _text: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(())
+    }
+    /// Sends the image at path in place of the previous preview, or as a new one.
+    /// Returns its id, None when the frontend does not show previews.
+    async fn send_preview(
+        &self,
+        _previous: Option<&str>,
+        _path: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(None)
+    }
+    /// Sends a finished picture with buttons under it, in place of its preview when there is one.
+    /// Frontends without buttons or spoilers just send the image.
+    async fn send_picture(
+        &self,
+        picture: &Picture,
+        preview: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match preview {
+            Some(id) => self.send_preview(Some(id), &picture.path).await.map(|_| ()),
+            None => self.send_image(&picture.path).await,
+        }
+    }
+    /// Sends the pictures of a batch together, the first in place of the preview.
+    /// Frontends without albums send them one by one.
+    async fn send_album(
+        &self,
+        pictures: &[Picture],
+        preview: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for (index, picture) in pictures.iter().enumerate() {
+            let preview = if index == 0 { preview } else { None };
+            self.send_picture(picture, preview).await?;
+        }
+        Ok(())
+    }
+    /// which history to use, None is the default history of the character
+    fn conversation(&self) -> Option<String> {
+        None
+    }
+    /// where to find this chat again after a restart, e.g. "telegram:1234"
+    fn address(&self) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,504 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use oobabooga_rs::History;
+use teloxide::{
+    net::Download,
+    prelude::*,
+    types::{
+        InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaDocument,
+        InputMediaPhoto, MediaKind::Audio, MediaKind::Document, MediaKind::Photo, MediaKind::Voice,
+        MediaPhoto, MessageId,
+    },
+};
+use tokio::fs;
+
+use crate::{
+    ai::vision::vision_enabled,
+    character,
+    config::get_ini_value,
+    formatting, history,
+    message_parsers::user_asked_for_edit,
+    pipeline::{
+        ai_reply, edit_reply, handle_command, image_action, image_tool, photo_reply, queue,
+        voice_reply,
+    },
+    transport::{Picture, Transport},
+};
+
+/// a telegram chat the character talks in
+pub struct TelegramTransport {
+    pub bot: Bot,
+    pub chat_id: ChatId,
+}
+
+#[async_trait]
+impl Transport for TelegramTransport {
+    /// sends text in parts telegram accepts, formatted when possible and as plain text otherwise
+    async fn send_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for part in formatting::split_message(text, formatting::TELEGRAM_MAX_LENGTH) {
+            if let Some(parse_mode) = formatting::parse_mode() {
+                let formatted = formatting::format(&part, parse_mode);
+                if formatted.chars().count() <= formatting::TELEGRAM_MAX_LENGTH {
+                    let res = self
+                        .bot
+                        .send_message(self.chat_id, formatted)
+                        .parse_mode(parse_mode)
+                        .await;
+                    match res {
+                        Ok(_) => continue,
+                        Err(e) => {
+                            log::error!(
+                                "could not send formatted message, sending plain text {:?}",
+                                e
+                            );
+                        }
+                    }
+                }
+            }
+            self.bot.send_message(self.chat_id, part).await?;
+        }
+        Ok(())
+    }
+    async fn send_image(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.bot.send_photo(self.chat_id, input_file(path)).await?;
+        Ok(())
+    }
+    async fn send_status(
+        &self,
+        text: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let message = self.bot.send_message(self.chat_id, text).await?;
+        Ok(Some(message.id.0.to_string()))
+    }
+    async fn edit_status(
+        &self,
+        id: &str,
+        text: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.bot
+            .edit_message_text(self.chat_id, MessageId(id.parse()?), text)
+            .await?;
+        Ok(())
+    }
+    /// the preview photo is edited in place, so the final image replaces it
+    async fn send_preview(
+        &self,
+        previous: Option<&str>,
+        path: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let message = match previous {
+            Some(id) => {
+                let media = InputMedia::Photo(InputMediaPhoto::new(input_file(path)));
+                self.bot
+                    .edit_message_media(self.chat_id, MessageId(id.parse()?), media)
+                    .await?
+            }
+            None => self.bot.send_photo(self.chat_id, input_file(path)).await?,
+        };
+        Ok(Some(message.id.0.to_string()))
+    }
+    /// The buttons are an inline keyboard. As a document the png keeps its
+    /// generation parameters, telegram recompresses photos. Documents cannot be
+    /// spoilers, so a blurred picture is always a photo.
+    async fn send_picture(
+        &self,
+        picture: &Picture,
+        preview: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = &picture.path;
+        let keyboard = InlineKeyboardMarkup::new([picture
+            .buttons
+            .iter()
+            .map(|button| InlineKeyboardButton::callback(&button.label, &button.action))
+            .collect::<Vec<_>>()]);
+        if images_as_documents() && !picture.spoiler {
+            self.bot
+                .send_document(self.chat_id, input_file(path))
+                .reply_markup(keyboard)
+                .await?;
+            if let Some(id) = preview {
+                self.bot
+                    .delete_message(self.chat_id, MessageId(id.parse()?))
+                    .await?;
+            }
+            return Ok(());
+        }
+        match preview {
+            Some(id) => {
+                let mut photo = InputMediaPhoto::new(input_file(path));
+                photo.has_spoiler = picture.spoiler;
+                self.bot
+                    .edit_message_media(
+                        self.chat_id,
+                        MessageId(id.parse()?),
+                        InputMedia::Photo(photo),
+                    )
+                    .reply_markup(keyboard)
+                    .await?;
+            }
+            None => {
+                self.bot
+                    .send_photo(self.chat_id, input_file(path))
+                    .has_spoiler(picture.spoiler)
+                    .reply_markup(keyboard)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+    /// Albums cannot have buttons, so a message after them has a row of buttons
+    /// for every picture, numbered like the album.
+    async fn send_album(
+        &self,
+        pictures: &[Picture],
+        preview: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // an album cannot mix documents with photos, which spoilers need
+        let as_documents = images_as_documents() && !pictures.iter().any(|picture| picture.spoiler);
+        // telegram takes up to 10 pictures in one album
+        for album in pictures.chunks(10) {
+            let media = album.iter().map(|picture| {
+                let file = input_file(&picture.path);
+                if as_documents {
+                    InputMedia::Document(InputMediaDocument::new(file))
+                } else {
+                    let mut photo = InputMediaPhoto::new(file);
+                    photo.has_spoiler = picture.spoiler;
+                    InputMedia::Photo(photo)
+                }
+            });
+            self.bot.send_media_group(self.chat_id, media).await?;
+        }
+        if let Some(id) = preview {
+            self.bot
+                .delete_message(self.chat_id, MessageId(id.parse()?))
+                .await?;
+        }
+        let keyboard =
+            InlineKeyboardMarkup::new(pictures.iter().enumerate().map(|(index, picture)| {
+                picture
+                    .buttons
+                    .iter()
+                    .map(|button| {
+                        InlineKeyboardButton::callback(
+                            format!("{} {}", index + 1, button.label),
+                            &button.action,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            }));
+        self.bot
+            .send_message(self.chat_id, format!("{} pictures", pictures.len()))
+            .reply_markup(keyboard)
+            .await?;
+        Ok(())
+    }
+    async fn send_voice(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.bot.send_voice(self.chat_id, input_file(path)).await?;
+        Ok(())
+    }
+    async fn send_sticker(
+        &self,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.bot
+            .send_sticker(self.chat_id, input_file(path))
+            .await?;
+        Ok(())
+    }
+    fn address(&self) -> Option<String> {
+        Some(format!("telegram:{}", self.chat_id.0))
+    }
+}
+
+/// a self hosted bot api server started with --local shares its files with the bot
+fn local_mode() -> bool {
+    get_ini_value("telegram", "local_mode").unwrap_or_default() == "true"
+}
+
+/// on by default, telegram recompresses photos, which loses the parameters in the png
+fn images_as_documents() -> bool {
+    get_ini_value("telegram", "images_as_documents").unwrap_or("true".to_string()) == "true"
+}
+
+/// the bot, talking to the configured bot api server instead of telegram's when there is one
+pub fn bot(token: String) -> Bot {
+    let bot = Bot::new(token);
+    match get_ini_value("telegram", "api_url").filter(|url| !url.is_empty()) {
+        Some(url) => match reqwest::Url::parse(&url) {
+            Ok(url) => bot.set_api_url(url),
+            Err(e) => {
+                log::error!("invalid bot api url {}: {:?}", url, e);
+                bot
+            }
+        },
+        None => bot,
+    }
+}
+
+/// in local mode the bot api server reads the file itself, so there is no upload size limit
+fn input_file(path: &str) -> InputFile {
+    if local_mode() {
+        let url = std::fs::canonicalize(path)
+            .ok()
+            .and_then(|path| reqwest::Url::from_file_path(path).ok());
+        match url {
+            Some(url) => return InputFile::url(url),
+            None => log::error!("could not find {}, uploading it instead", path),
+        }
+    }
+    InputFile::file(path)
+}
+
+/// Downloads a file from telegram. In local mode the bot api server returns
+/// the absolute path of the file on disk, which is read directly,
+/// so files over the 20 MB download limit work too.
+pub async fn download(
+    bot: &Bot,
+    path: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    if local_mode() && std::path::Path::new(path).is_absolute() {
+        return Ok(fs::read(path).await?);
+    }
+    let mut data: Vec<u8> = vec![];
+    bot.download_file(path, &mut data).await?;
+    Ok(data)
+}
+
+/// the first photo of an album, waiting for the second one
+struct AlbumPhoto {
+    group: String,
+    data: Vec<u8>,
+    caption: Option<String>,
+}
+
+static ALBUM: Mutex<Option<AlbumPhoto>> = Mutex::new(None);
+
+/// Photos with a caption like "make this anime style" go through img2img, she looks at the others.
+/// An album of two photos is a photo and its inpainting mask, telegram sends them as two messages.
+async fn photo_received(
+    transport: &TelegramTransport,
+    photo: MediaPhoto,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let largest = match photo.photo.last() {
+        Some(largest) => largest,
+        None => return Ok(()),
+    };
+    let file = transport.bot.get_file(largest.file.id.clone()).await?;
+    let data = download(&transport.bot, &file.path).await?;
+    let (image, mask, caption) = match photo.media_group_id {
+        None => (data, None, photo.caption),
+        Some(group) => {
+            let first = ALBUM
+                .lock()
+                .unwrap()
+                .take()
+                .filter(|first| first.group == group);
+            match first {
+                Some(first) => (first.data, Some(data), first.caption.or(photo.caption)),
+                None => {
+                    *ALBUM.lock().unwrap() = Some(AlbumPhoto {
+                        group,
+                        data,
+                        caption: photo.caption,
+                    });
+                    return Ok(());
+                }
+            }
+        }
+    };
+    let caption = caption.unwrap_or_default();
+    if image_tool(transport, &caption, Some(&image)).await? {
+        Ok(())
+    } else if user_asked_for_edit(&caption) {
+        edit_reply(transport, &caption, &image, mask.as_deref()).await
+    } else if vision_enabled() {
+        let history = history::file::read_json_from_file(None).unwrap_or(History {
+            internal: vec![],
+            visible: vec![],
+        });
+        photo_reply(transport, &caption, &image, history).await
+    } else {
+        log::info!("photo received without an edit request");
+        Ok(())
+    }
+}
+
+pub async fn run() {
+    let token = get_ini_value("telegram", "token");
+    match token {
+        Some(t) => {
+            let bot = bot(t);
+            let resume_bot = bot.clone();
+            tokio::spawn(async move {
+                queue::resume("telegram", |address| {
+                    let chat_id = address.strip_prefix("telegram:")?.parse().ok()?;
+                    Some(Box::new(TelegramTransport {
+                        bot: resume_bot.clone(),
+                        chat_id: ChatId(chat_id),
+                    }))
+                })
+                .await
+            });
+
+            let handler = dptree::entry()
+                .branch(Update::filter_message().endpoint(message_received))
+                .branch(Update::filter_callback_query().endpoint(button_pressed));
+            Dispatcher::builder(bot, handler)
+                .default_handler(|_| async {})
+                .enable_ctrlc_handler()
+                .build()
+                .dispatch()
+                .await;
+        }
+        None => {
+            log::error!("No token found");
+        }
+    }
+}
+
+/// a message in a chat, only the user from the config gets an answer
+async fn message_received(bot: Bot, msg: Message) -> ResponseResult<()> {
+    let _history = history::file::lock(None).await;
+    let opt_history = history::file::read_json_from_file(None);
+    let mut history = History {
+        internal: vec![],
+        visible: vec![],
+    };
+    match opt_history {
+        Some(h) => {
+            history = h;
+        }
+        None => {
+            log::error!("No history found");
+        }
+    }
+    let user = msg.from().unwrap().username.as_ref().unwrap();
+    let chat_id = msg.chat.id;
+    let transport = TelegramTransport {
+        bot: bot.clone(),
+        chat_id,
+    };
+
+    let message_text = msg.text();
+    // only send when user is the same as in the config
+    if user == &get_ini_value("telegram", "user").unwrap() {
+        match message_text {
+            Some(text) => {
+                if text.starts_with('/') {
+                    let res = handle_command(&transport, text).await;
+                    match res {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            if text == "/import_character" {
+                                if let Err(e) = transport.send_text("Send the character card (json or png) as a file with /import_character as caption.").await {
+                                    log::error!("{:?}", e);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("{}", e);
+                        }
+                    }
+                } else {
+                    let res = ai_reply(&transport, text, history).await;
+                    match res {
+                        Ok(_) => {
+                            //lol seems like it always returns Ok
+                            log::info!("ai has replied")
+                        }
+                        Err(e) => {
+                            log::error!("Error: {}", e)
+                        }
+                    }
+                }
+            }
+
+            None => match msg.kind {
+                teloxide::types::MessageKind::Common(msg_common) => match msg_common.media_kind {
+                    Audio(audio) => {
+                        log::info!("audio received {:?}", audio.audio.file);
+                    }
+                    Document(document) => {
+                        if document.caption.as_deref() == Some("/import_character") {
+                            let reply = match import_character(&bot, &document.document).await {
+                                Ok(name) => format!("{} has been imported.", name),
+                                Err(e) => {
+                                    log::error!("could not import character {:?}", e);
+                                    format!("could not import character: {}", e)
+                                }
+                            };
+                            if let Err(e) = transport.send_text(&reply).await {
+                                log::error!("{:?}", e);
+                            }
+                        } else {
+                            log::info!("document received {:?}", document.document.file_name);
+                        }
+                    }
+                    Voice(voice) => {
+                        let res = bot.get_file(voice.voice.file.id).await;
+                        match res {
+                            Ok(file) => {
+                                let res = download(&bot, &file.path).await;
+                                match res {
+                                    Ok(data) => {
+                                        log::info!("audio downloaded");
+                                        voice_reply(&transport, data, history).await;
+                                    }
+                                    Err(e) => {
+                                        log::error!("Error downloading file {:?}", e);
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                log::error!("Error getting file {:?}", e);
+                            }
+                        }
+                    }
+                    Photo(photo) => {
+                        if let Err(e) = photo_received(&transport, photo).await {
+                            log::error!("could not edit photo {:?}", e);
+                        }
+                    }
+
+                    _ => log::info!("unknown message type"),
+                },
+                _ => log::info!("unknown message type {:?}", msg),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// a button under a picture was pressed
+async fn button_pressed(bot: Bot, query: CallbackQuery) -> ResponseResult<()> {
+    bot.answer_callback_query(query.id.clone()).await?;
+    // only the user from the config may use them
+    if query.from.username != get_ini_value("telegram", "user") {
+        return Ok(());
+    }
+    if let (Some(message), Some(action)) = (query.message, query.data) {
+        let transport = TelegramTransport {
+            bot,
+            chat_id: message.chat.id,
+        };
+        if let Err(e) = image_action(&transport, &action).await {
+            log::error!("{:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// downloads a character card and makes it the character the bot uses
+async fn import_character(
+    bot: &Bot,
+    document: &teloxide::types::Document,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let file = bot.get_file(&document.file.id).await?;
+    let data = download(bot, &file.path).await?;
+    let character = character::parse_card(&data)?;
+    character::save_character(&character)?;
+    Ok(character.name)
+}